@@ -1,7 +1,6 @@
-use xladd::registrator::Reg;
 use xladd::variant::Variant;
 use xladd::xlcall::LPXLOPER12;
-use xladd_derive::xl_func;
+use xladd_derive::{xl_addin, xl_func};
 
 #[xl_func()]
 fn add(arg1: f64, arg2: f64) -> Result<f64, Box<dyn std::error::Error>> {
@@ -52,13 +51,8 @@ fn add_f64_2(a: Array2<f64>) -> Result<Array2<f64>, Box<dyn std::error::Error>>
     Ok(Array2::from_elem([2, 2], 0.0f64))
 }
 
-// Don't forget to register your functions
-#[no_mangle]
-pub extern "stdcall" fn xlAutoOpen() -> i32 {
-    let r = Reg::new();
-    #[cfg(feature = "use_ndarray")]
-    register_add_f64_2(&r);
-    1
-}
+// Generates xlAutoOpen, which registers every xl_func above, and the other XLL exports
+#[xl_addin(name = "Train")]
+fn setup() {}
 
 fn main() {} // Not needed for actual dll
@@ -0,0 +1,18 @@
+use crate::coerce::{self, Coerce};
+use crate::convert::IntoXl;
+use crate::error::XlError;
+use crate::memory;
+use xladd::variant::Variant;
+use xladd::xlcall::LPXLOPER12;
+
+/// Answers `xlAddInManagerInfo12`, which Excel calls with an action of 1 to get the name
+/// to show in the Add-In Manager
+pub fn addin_manager_info(action: LPXLOPER12, name: &str) -> LPXLOPER12 {
+    let action = Variant::from(action);
+    let info = if coerce::number(&action, Coerce::Lenient).is_ok_and(|n| n == 1.0) {
+        Variant::from(name)
+    } else {
+        XlError::Value.into_xl()
+    };
+    memory::return_owned(info)
+}
@@ -0,0 +1,137 @@
+use crate::convert::{IntoXl, XlScalar};
+use crate::error::XlError;
+use crate::options::{Padding, ReturnOptions};
+use xladd::variant::Variant;
+
+/// A single cell of any type, for returning grids that mix labels, numbers, errors and
+/// blanks. Return them as `Array2<XlCell>` or `Vec<Vec<XlCell>>`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum XlCell {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(XlError),
+    /// A cell that looks blank. It holds empty text, so ISBLANK is FALSE for it
+    #[default]
+    Empty,
+}
+
+impl IntoXl for XlCell {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        match self {
+            XlCell::Number(n) => Variant::from(n),
+            XlCell::Text(s) => s.into_xl_with(opts),
+            XlCell::Bool(b) => Variant::from(b),
+            XlCell::Error(e) => e.into_xl(),
+            XlCell::Empty => empty(),
+        }
+    }
+}
+
+impl XlScalar for XlCell {}
+
+impl From<f64> for XlCell {
+    fn from(n: f64) -> XlCell {
+        XlCell::Number(n)
+    }
+}
+
+impl From<String> for XlCell {
+    fn from(s: String) -> XlCell {
+        XlCell::Text(s)
+    }
+}
+
+impl From<&str> for XlCell {
+    fn from(s: &str) -> XlCell {
+        XlCell::Text(s.to_string())
+    }
+}
+
+impl From<bool> for XlCell {
+    fn from(b: bool) -> XlCell {
+        XlCell::Bool(b)
+    }
+}
+
+impl From<XlError> for XlCell {
+    fn from(e: XlError) -> XlCell {
+        XlCell::Error(e)
+    }
+}
+
+impl<T: Into<XlCell>> From<Option<T>> for XlCell {
+    fn from(v: Option<T>) -> XlCell {
+        v.map_or(XlCell::Empty, Into::into)
+    }
+}
+
+/// The closest a result can get to a blank cell. Excel shows a returned `xltypeNil` as 0,
+/// so empty text is used instead.
+pub(crate) fn empty() -> Variant {
+    Variant::from("")
+}
+
+/// The value that fills a missing cell, or `None` when a ragged result is an error
+fn padding(padding: Padding) -> Option<Variant> {
+    match padding {
+        Padding::NA => Some(XlError::NA.into_xl()),
+        Padding::Blank => Some(empty()),
+        Padding::Number(n) => Some(Variant::from(n)),
+        Padding::Text(s) => Some(Variant::from(s)),
+        Padding::Error => None,
+    }
+}
+
+/// Lays out `cells` row by row with `columns` columns, filling the last row as `padding` says
+pub(crate) fn grid(mut cells: Vec<Variant>, columns: usize, opts: &ReturnOptions) -> Variant {
+    let columns = columns.max(1);
+    let rows = cells.len().div_ceil(columns);
+    if cells.len() < rows * columns {
+        match padding(opts.padding) {
+            Some(pad) => cells.resize(rows * columns, pad),
+            None => {
+                log::error!(
+                    "{} values don't fill rows of {} columns",
+                    cells.len(),
+                    columns
+                );
+                return XlError::Value.into_xl();
+            }
+        }
+    }
+    Variant::from_array((rows, columns), &cells)
+}
+
+/// Lays out one list of cells per row, filling short rows as `padding` says
+pub(crate) fn rows(rows: Vec<Vec<Variant>>, opts: &ReturnOptions) -> Variant {
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return XlError::NA.into_xl();
+    }
+    let height = rows.len();
+    let mut cells = Vec::with_capacity(height * columns);
+    for (i, row) in rows.into_iter().enumerate() {
+        let len = row.len();
+        cells.extend(row);
+        if len < columns {
+            match padding(opts.padding) {
+                Some(pad) => cells.resize(cells.len() + columns - len, pad),
+                None => {
+                    log::error!(
+                        "Row {} has {} values but the widest row has {}",
+                        i + 1,
+                        len,
+                        columns
+                    );
+                    return XlError::Value.into_xl();
+                }
+            }
+        }
+    }
+    Variant::from_array((height, columns), &cells)
+}
@@ -0,0 +1,186 @@
+use crate::convert::ConversionError;
+use std::convert::TryFrom;
+use xladd::variant::Variant;
+use xladd::xlcall::{
+    xlbitDLLFree, xlbitXLFree, xltypeBool, xltypeErr, xltypeInt, xltypeMissing, xltypeNil, xltypeNum,
+    xltypeStr,
+};
+
+/// How strictly a cell has to match the type of the parameter it is read into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coerce {
+    /// The Excel type must match: numbers for numeric parameters, TRUE/FALSE for `bool`
+    /// and text for strings
+    Strict,
+    /// Text holding a number, a percentage or TRUE/FALSE is parsed, booleans count as 1/0
+    /// and numbers/booleans are formatted for string parameters
+    Lenient,
+}
+
+/// A single cell, as read from the XLOPER12 behind a variant
+pub(crate) enum Value {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error,
+    Empty,
+    Other,
+}
+
+impl Value {
+    pub(crate) fn read(v: &Variant) -> Result<Value, ConversionError> {
+        let xloper = v.as_xloper();
+        let xltype = xloper.xltype & !(xlbitDLLFree | xlbitXLFree);
+        Ok(if xltype == xltypeNum || xltype == xltypeInt {
+            Value::Number(f64::try_from(v)?)
+        } else if xltype == xltypeStr {
+            Value::Text(String::try_from(v)?)
+        } else if xltype == xltypeBool {
+            Value::Bool(unsafe { xloper.val.xbool } != 0)
+        } else if xltype == xltypeErr {
+            Value::Error
+        } else if xltype == xltypeMissing || xltype == xltypeNil {
+            Value::Empty
+        } else {
+            Value::Other
+        })
+    }
+
+    fn describe(&self) -> &'static str {
+        match self {
+            Value::Number(_) => "a number",
+            Value::Text(_) => "text",
+            Value::Bool(_) => "TRUE/FALSE",
+            Value::Error => "an error",
+            Value::Empty => "an empty cell",
+            Value::Other => "a range",
+        }
+    }
+}
+
+fn mismatch(expected: &str, got: &Value) -> ConversionError {
+    ConversionError::new(format!("Expected {} but got {}", expected, got.describe()))
+}
+
+pub(crate) fn number(v: &Variant, coerce: Coerce) -> Result<f64, ConversionError> {
+    match (Value::read(v)?, coerce) {
+        (Value::Number(n), _) => Ok(n),
+        (Value::Bool(b), Coerce::Lenient) => Ok(if b { 1.0 } else { 0.0 }),
+        (Value::Text(s), Coerce::Lenient) => parse_number(&s)
+            .ok_or_else(|| ConversionError::new(format!("Could not read \"{}\" as a number", s))),
+        (value, _) => Err(mismatch("a number", &value)),
+    }
+}
+
+pub(crate) fn boolean(v: &Variant, coerce: Coerce) -> Result<bool, ConversionError> {
+    match (Value::read(v)?, coerce) {
+        (Value::Bool(b), _) => Ok(b),
+        (Value::Number(n), Coerce::Lenient) if n == 1.0 || n == 0.0 => Ok(n == 1.0),
+        (Value::Text(s), Coerce::Lenient) => match s.trim().to_ascii_uppercase().as_str() {
+            "TRUE" | "1" => Ok(true),
+            "FALSE" | "0" => Ok(false),
+            _ => Err(ConversionError::new(format!("Could not read \"{}\" as TRUE/FALSE", s))),
+        },
+        (value, _) => Err(mismatch("TRUE/FALSE", &value)),
+    }
+}
+
+pub(crate) fn text(v: &Variant, coerce: Coerce) -> Result<String, ConversionError> {
+    match (Value::read(v)?, coerce) {
+        (Value::Text(s), _) => Ok(s),
+        (Value::Number(n), Coerce::Lenient) => Ok(format_number(n)),
+        (Value::Bool(b), Coerce::Lenient) => Ok(if b { "TRUE" } else { "FALSE" }.to_string()),
+        (Value::Empty, Coerce::Lenient) => Ok(String::new()),
+        (value, _) => Err(mismatch("text", &value)),
+    }
+}
+
+/// Numbers as Excel would type them: `1.5`, ` 2 `, `12.5%`, `TRUE`/`FALSE`
+fn parse_number(s: &str) -> Option<f64> {
+    let s = s.trim();
+    if let Some(pct) = s.strip_suffix('%') {
+        return pct.trim_end().parse::<f64>().ok().map(|n| n / 100.0);
+    }
+    match s.to_ascii_uppercase().as_str() {
+        "TRUE" => Some(1.0),
+        "FALSE" => Some(0.0),
+        _ => s.parse::<f64>().ok().filter(|n| n.is_finite()),
+    }
+}
+
+/// A number as Excel writes it in text: 15 significant digits, switching to `1E+21` style
+/// for very large and very small values
+pub(crate) fn format_number(n: f64) -> String {
+    if !n.is_finite() {
+        return n.to_string();
+    }
+    if n == 0.0 {
+        return "0".to_string();
+    }
+    // Rounding to 15 digits first gives the exponent of the number that is shown
+    let sci = format!("{:.14e}", n);
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let exp: i32 = exp[1..].parse().unwrap();
+    if (-5..15).contains(&exp) {
+        let decimals = (14 - exp) as usize;
+        trim_zeros(&format!("{:.*}", decimals, n)).to_string()
+    } else {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}E{}{:02}", trim_zeros(mantissa), sign, exp.abs())
+    }
+}
+
+fn trim_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_number, parse_number};
+
+    #[test]
+    fn parses_numbers() {
+        assert_eq!(parse_number("1.5"), Some(1.5));
+        assert_eq!(parse_number(" 2 "), Some(2.0));
+        assert_eq!(parse_number("-3"), Some(-3.0));
+        assert_eq!(parse_number("1e3"), Some(1000.0));
+        assert_eq!(parse_number("1.5E-2"), Some(0.015));
+    }
+
+    #[test]
+    fn parses_percentages() {
+        assert_eq!(parse_number("12.5%"), Some(0.125));
+        assert_eq!(parse_number(" 50 % "), Some(0.5));
+        assert_eq!(parse_number("-100%"), Some(-1.0));
+        assert_eq!(parse_number("%"), None);
+    }
+
+    #[test]
+    fn parses_booleans() {
+        assert_eq!(parse_number("TRUE"), Some(1.0));
+        assert_eq!(parse_number("false"), Some(0.0));
+    }
+
+    #[test]
+    fn rejects_other_text() {
+        assert_eq!(parse_number(""), None);
+        assert_eq!(parse_number("abc"), None);
+        assert_eq!(parse_number("1,000"), None);
+        assert_eq!(parse_number("inf"), None);
+        assert_eq!(parse_number("NaN"), None);
+    }
+
+    #[test]
+    fn formats_numbers_like_excel() {
+        assert_eq!(format_number(2.0), "2");
+        assert_eq!(format_number(-0.5), "-0.5");
+        assert_eq!(format_number(0.1 + 0.2), "0.3");
+        assert_eq!(format_number(1e21), "1E+21");
+        assert_eq!(format_number(1.5e-7), "1.5E-07");
+        assert_eq!(format_number(0.0), "0");
+    }
+}
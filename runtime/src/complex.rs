@@ -0,0 +1,188 @@
+use crate::coerce::{format_number, Value};
+use crate::convert::{cells_from_xl, ConversionError, FromXl, IntoXl, XlScalar};
+use crate::options::ConvertOptions;
+use num_complex::Complex64;
+use xladd::variant::Variant;
+
+/// Reads a complex number written the way Excel's COMPLEX function writes it: `3+4i`,
+/// `-2.5j`, `i`, `1e-3-i` or a plain number
+pub fn parse_complex(s: &str) -> Option<Complex64> {
+    let s = s.trim();
+    let body = match s.strip_suffix('i').or_else(|| s.strip_suffix('j')) {
+        Some(body) => body,
+        None => return s.parse().ok().map(|re| Complex64::new(re, 0.0)),
+    };
+    // The imaginary part starts at the last sign that isn't part of an exponent
+    let split = body
+        .char_indices()
+        .skip(1)
+        .filter(|&(i, c)| (c == '+' || c == '-') && !matches!(body.as_bytes()[i - 1], b'e' | b'E'))
+        .last()
+        .map(|(i, _)| i);
+    let (re, im) = match split {
+        Some(i) => (body[..i].parse().ok()?, &body[i..]),
+        None => (0.0, body),
+    };
+    let im = match im {
+        "" | "+" => 1.0,
+        "-" => -1.0,
+        im => im.parse().ok()?,
+    };
+    Some(Complex64::new(re, im))
+}
+
+/// Writes a complex number the way Excel's COMPLEX function does, so `3+4i`, `-i` or `2`
+pub fn format_complex(c: Complex64) -> String {
+    let re = format_number(c.re);
+    let im = match format_number(c.im).as_str() {
+        "0" => return re,
+        "1" => String::new(),
+        "-1" => "-".to_string(),
+        im => im.to_string(),
+    };
+    if re == "0" {
+        format!("{}i", im)
+    } else if c.im > 0.0 {
+        format!("{}+{}i", re, im)
+    } else {
+        format!("{}{}i", re, im)
+    }
+}
+
+fn pair_from_xl(v: &Variant, row: usize) -> Result<Option<Complex64>, ConversionError> {
+    match (Value::read(&v.at(row, 0))?, Value::read(&v.at(row, 1))?) {
+        (Value::Number(re), Value::Number(im)) => Ok(Some(Complex64::new(re, im))),
+        _ => Ok(None),
+    }
+}
+
+impl FromXl for Complex64 {
+    fn from_xl(v: &Variant) -> Result<Complex64, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, _opts: &ConvertOptions) -> Result<Complex64, ConversionError> {
+        // A pair of cells holding the real and imaginary parts
+        if v.dim() == (1, 2) {
+            if let Some(c) = pair_from_xl(v, 0)? {
+                return Ok(c);
+            }
+        }
+        match Value::read(v)? {
+            Value::Number(re) => Ok(Complex64::new(re, 0.0)),
+            Value::Text(s) => parse_complex(&s)
+                .ok_or_else(|| ConversionError::new(format!("Could not read \"{}\" as a complex number", s))),
+            _ => Err(ConversionError::new("Expected a complex number such as 3+4i")),
+        }
+    }
+
+    fn vec_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Vec<Complex64>, ConversionError> {
+        // Two numeric columns hold the real and imaginary parts, otherwise every cell is
+        // a complex number in its own right
+        let (rows, columns) = v.dim();
+        if columns == 2 {
+            let pairs = (0..rows).map(|row| pair_from_xl(v, row)).collect::<Result<Option<Vec<_>>, _>>()?;
+            if let Some(pairs) = pairs {
+                return Ok(pairs);
+            }
+        }
+        cells_from_xl(v, opts)
+    }
+}
+
+impl IntoXl for Complex64 {
+    fn into_xl(self) -> Variant {
+        Variant::from(format_complex(self))
+    }
+}
+
+impl XlScalar for Complex64 {}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_complex, parse_complex};
+    use num_complex::Complex64;
+
+    fn c(re: f64, im: f64) -> Option<Complex64> {
+        Some(Complex64::new(re, im))
+    }
+
+    #[test]
+    fn parses_both_parts() {
+        assert_eq!(parse_complex("3+4i"), c(3.0, 4.0));
+        assert_eq!(parse_complex("3-4i"), c(3.0, -4.0));
+        assert_eq!(parse_complex("-3-4i"), c(-3.0, -4.0));
+        assert_eq!(parse_complex(" 1.5+2.5j "), c(1.5, 2.5));
+    }
+
+    #[test]
+    fn parses_a_bare_imaginary_unit() {
+        assert_eq!(parse_complex("i"), c(0.0, 1.0));
+        assert_eq!(parse_complex("-i"), c(0.0, -1.0));
+        assert_eq!(parse_complex("+j"), c(0.0, 1.0));
+        assert_eq!(parse_complex("2+i"), c(2.0, 1.0));
+        assert_eq!(parse_complex("2-j"), c(2.0, -1.0));
+    }
+
+    #[test]
+    fn parses_one_part() {
+        assert_eq!(parse_complex("2.5"), c(2.5, 0.0));
+        assert_eq!(parse_complex("-2.5"), c(-2.5, 0.0));
+        assert_eq!(parse_complex("4i"), c(0.0, 4.0));
+        assert_eq!(parse_complex("-4j"), c(0.0, -4.0));
+    }
+
+    #[test]
+    fn parses_exponents() {
+        assert_eq!(parse_complex("1e-3-i"), c(0.001, -1.0));
+        assert_eq!(parse_complex("1E+3+2E-2i"), c(1000.0, 0.02));
+        assert_eq!(parse_complex("-2e3i"), c(0.0, -2000.0));
+    }
+
+    #[test]
+    fn rejects_malformed_text() {
+        assert_eq!(parse_complex(""), None);
+        assert_eq!(parse_complex("abc"), None);
+        assert_eq!(parse_complex("3+4k"), None);
+        assert_eq!(parse_complex("3+xi"), None);
+        assert_eq!(parse_complex("3++4i"), None);
+    }
+
+    #[test]
+    fn formats_like_excel() {
+        assert_eq!(format_complex(Complex64::new(3.0, 4.0)), "3+4i");
+        assert_eq!(format_complex(Complex64::new(3.0, -4.0)), "3-4i");
+        assert_eq!(format_complex(Complex64::new(2.0, 0.0)), "2");
+        assert_eq!(format_complex(Complex64::new(0.0, 0.0)), "0");
+        assert_eq!(format_complex(Complex64::new(0.0, 1.0)), "i");
+        assert_eq!(format_complex(Complex64::new(0.0, -1.0)), "-i");
+        assert_eq!(format_complex(Complex64::new(2.0, 1.0)), "2+i");
+        assert_eq!(format_complex(Complex64::new(-2.5, -1.0)), "-2.5-i");
+        assert_eq!(format_complex(Complex64::new(0.0, 2.5)), "2.5i");
+    }
+
+    #[test]
+    fn formats_15_significant_digits() {
+        assert_eq!(format_complex(Complex64::new(0.1 + 0.2, 0.0)), "0.3");
+        assert_eq!(format_complex(Complex64::new(1.0 / 3.0, 0.0)), "0.333333333333333");
+        assert_eq!(format_complex(Complex64::new(123456789012345.0, 0.0)), "123456789012345");
+        assert_eq!(format_complex(Complex64::new(0.0, 1.0 + 1e-16)), "i");
+    }
+
+    #[test]
+    fn formats_large_and_small_numbers_with_an_exponent() {
+        assert_eq!(format_complex(Complex64::new(1e21, 0.0)), "1E+21");
+        assert_eq!(format_complex(Complex64::new(1234567890123456.0, 0.0)), "1.23456789012346E+15");
+        assert_eq!(format_complex(Complex64::new(1.5e-7, 0.0)), "1.5E-07");
+        assert_eq!(format_complex(Complex64::new(1.0, -2e-10)), "1-2E-10i");
+        assert_eq!(format_complex(Complex64::new(0.0001, 0.0)), "0.0001");
+    }
+
+    #[test]
+    fn formatted_values_read_back() {
+        for &(re, im) in &[(3.0, 4.0), (-1.5, -1.0), (0.0, 1e21), (1e-7, -2.5)] {
+            let z = Complex64::new(re, im);
+            assert_eq!(parse_complex(&format_complex(z)), Some(z));
+        }
+    }
+}
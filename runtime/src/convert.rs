@@ -0,0 +1,588 @@
+use crate::cell;
+use crate::coerce;
+use crate::error::XlError;
+use crate::limits;
+use crate::options::{ConvertOptions, Duplicates, Orient, ReturnOptions, UnitValue};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::time::{SystemTime, UNIX_EPOCH};
+use xladd::variant::Variant;
+
+#[cfg(feature = "use_ndarray")]
+use ndarray::Array2;
+
+/// Raised when an Excel value cannot be converted into the Rust type a function expects
+#[derive(Debug, Clone)]
+pub struct ConversionError(String);
+
+impl ConversionError {
+    pub fn new<S: Into<String>>(msg: S) -> ConversionError {
+        ConversionError(msg.into())
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+impl From<xladd::variant::XLAddError> for ConversionError {
+    fn from(e: xladd::variant::XLAddError) -> ConversionError {
+        ConversionError(e.to_string())
+    }
+}
+
+/// Conversion from an Excel value into a Rust parameter.
+///
+/// Implementing this for a type allows it to be used as a scalar parameter, as the element
+/// of a `&[T]` or `Vec<T>` parameter, as the key or value of a map parameter and, with
+/// `use_ndarray`, as the element of an `Array2<T>`.
+pub trait FromXl: Sized {
+    fn from_xl(v: &Variant) -> Result<Self, ConversionError>;
+
+    /// Converts honouring the options given on `xl_func`. Only needs implementing for
+    /// types that have something to configure.
+    fn from_xl_with(v: &Variant, _opts: &ConvertOptions) -> Result<Self, ConversionError> {
+        Self::from_xl(v)
+    }
+
+    /// Converts a range cell by cell, reading it row by row. Overridden by the types that
+    /// xladd can convert in bulk.
+    #[doc(hidden)]
+    fn vec_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Vec<Self>, ConversionError> {
+        cells_from_xl(v, opts)
+    }
+
+    #[cfg(feature = "use_ndarray")]
+    #[doc(hidden)]
+    fn array_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Array2<Self>, ConversionError> {
+        cells_into_array(v, opts)
+    }
+}
+
+pub(crate) fn cells_from_xl<T: FromXl>(v: &Variant, opts: &ConvertOptions) -> Result<Vec<T>, ConversionError> {
+    let (rows, columns) = v.dim();
+    // Dragging a formula sends the first cell as a single value rather than a range
+    if rows * columns <= 1 {
+        return Ok(vec![T::from_xl_with(v, opts)?]);
+    }
+    let mut res = Vec::with_capacity(rows * columns);
+    for row in 0..rows {
+        for col in 0..columns {
+            res.push(T::from_xl_with(&v.at(row, col), opts)?);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(feature = "use_ndarray")]
+fn cells_into_array<T: FromXl>(v: &Variant, opts: &ConvertOptions) -> Result<Array2<T>, ConversionError> {
+    let (rows, columns) = v.dim();
+    let cells = cells_from_xl(v, opts)?;
+    let shape = if cells.len() == rows * columns { (rows, columns) } else { (cells.len(), 1) };
+    Array2::from_shape_vec(shape, cells).map_err(|e| ConversionError::new(e.to_string()))
+}
+
+/// Conversion from a Rust return value into an Excel value.
+///
+/// Implementing this for a type allows it to be returned on its own. Types that fill a single
+/// cell also implement [`XlScalar`] so that they can be returned in a `Vec<T>`, a
+/// `(Vec<T>, usize)` tuple or, with `use_ndarray` and when they are `Clone`, an `Array2<T>`.
+pub trait IntoXl: Sized {
+    fn into_xl(self) -> Variant;
+
+    /// Converts honouring the options given on `xl_func`. Only needs implementing for
+    /// types whose layout can be configured.
+    fn into_xl_with(self, _opts: &ReturnOptions) -> Variant {
+        self.into_xl()
+    }
+
+    /// Lays out `v` as a 2d array with `columns` columns, or #N/A when it is empty. Overridden
+    /// by the types that xladd can convert in bulk.
+    #[doc(hidden)]
+    fn vec_into_xl(mut v: Vec<Self>, columns: usize, opts: &ReturnOptions) -> Variant {
+        if v.is_empty() {
+            return XlError::NA.into_xl();
+        }
+        let columns = match limits::fit_grid(&mut v, columns, opts) {
+            Some(columns) => columns,
+            None => return XlError::Value.into_xl(),
+        };
+        cell::grid(v.into_iter().map(|x| x.into_xl_with(opts)).collect(), columns, opts)
+    }
+
+    /// Lays out an array in its logical row by row order, whatever its memory layout
+    #[cfg(feature = "use_ndarray")]
+    #[doc(hidden)]
+    fn array_into_xl(a: Array2<Self>, opts: &ReturnOptions) -> Variant
+    where
+        Self: Clone,
+    {
+        let columns = a.ncols();
+        Self::vec_into_xl(a.iter().cloned().collect(), columns, opts)
+    }
+}
+
+impl FromXl for f64 {
+    fn from_xl(v: &Variant) -> Result<f64, ConversionError> {
+        Ok(f64::try_from(v)?)
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<f64, ConversionError> {
+        match opts.coerce {
+            Some(coerce) => coerce::number(v, coerce),
+            None => f64::from_xl(v),
+        }
+    }
+
+    fn vec_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Vec<f64>, ConversionError> {
+        match opts.coerce {
+            Some(_) => cells_from_xl(v, opts),
+            None => Ok(Vec::<f64>::try_from(v)?),
+        }
+    }
+
+    #[cfg(feature = "use_ndarray")]
+    fn array_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Array2<f64>, ConversionError> {
+        match opts.coerce {
+            Some(_) => cells_into_array(v, opts),
+            None => Ok(Array2::<f64>::try_from(v)?),
+        }
+    }
+}
+
+impl FromXl for String {
+    fn from_xl(v: &Variant) -> Result<String, ConversionError> {
+        Ok(String::try_from(v)?)
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<String, ConversionError> {
+        match opts.coerce {
+            Some(coerce) => coerce::text(v, coerce),
+            None => String::from_xl(v),
+        }
+    }
+
+    fn vec_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Vec<String>, ConversionError> {
+        match opts.coerce {
+            Some(_) => cells_from_xl(v, opts),
+            None => Ok(Vec::<String>::try_from(v)?),
+        }
+    }
+
+    #[cfg(feature = "use_ndarray")]
+    fn array_from_xl(v: &Variant, opts: &ConvertOptions) -> Result<Array2<String>, ConversionError> {
+        match opts.coerce {
+            Some(_) => cells_into_array(v, opts),
+            None => Ok(Array2::<String>::try_from(v)?),
+        }
+    }
+}
+
+impl FromXl for bool {
+    fn from_xl(v: &Variant) -> Result<bool, ConversionError> {
+        Ok(bool::try_from(v)?)
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<bool, ConversionError> {
+        match opts.coerce {
+            Some(coerce) => coerce::boolean(v, coerce),
+            None => bool::from_xl(v),
+        }
+    }
+}
+
+impl FromXl for f32 {
+    fn from_xl(v: &Variant) -> Result<f32, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<f32, ConversionError> {
+        Ok(f64::from_xl_with(v, opts)? as f32)
+    }
+}
+
+// Excel only has doubles, so integers are accepted when they are whole and in range
+macro_rules! integer_from_xl {
+    ($($t:ty),*) => {
+        $(
+            impl FromXl for $t {
+                fn from_xl(v: &Variant) -> Result<$t, ConversionError> {
+                    Self::from_xl_with(v, &ConvertOptions::default())
+                }
+
+                fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<$t, ConversionError> {
+                    let f = f64::from_xl_with(v, opts)?;
+                    if f.fract() != 0.0 || f < <$t>::MIN as f64 || f > <$t>::MAX as f64 {
+                        return Err(ConversionError::new(format!(
+                            "{} is not a valid {}",
+                            f,
+                            stringify!($t)
+                        )));
+                    }
+                    Ok(f as $t)
+                }
+            }
+        )*
+    };
+}
+
+integer_from_xl!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl<T: FromXl> FromXl for Vec<T> {
+    fn from_xl(v: &Variant) -> Result<Vec<T>, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<Vec<T>, ConversionError> {
+        T::vec_from_xl(v, opts)
+    }
+}
+
+#[cfg(feature = "use_ndarray")]
+impl<T: FromXl> FromXl for Array2<T> {
+    fn from_xl(v: &Variant) -> Result<Array2<T>, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<Array2<T>, ConversionError> {
+        T::array_from_xl(v, opts)
+    }
+}
+
+/// Reads a two column range (or a two row range) as key/value pairs, skipping rows without
+/// a key and applying the duplicate key policy through `insert`
+fn pairs_from_xl<K: FromXl, V: FromXl>(
+    v: &Variant,
+    opts: &ConvertOptions,
+    mut insert: impl FnMut(K, V) -> bool,
+) -> Result<(), ConversionError> {
+    let (rows, columns) = v.dim();
+    let (pairs, by_row) = match (rows, columns) {
+        (_, 2) => (rows, true),
+        (2, _) => (columns, false),
+        _ => {
+            return Err(ConversionError::new(format!(
+                "Expected a two column or two row range for a map but got {}x{}",
+                rows, columns
+            )))
+        }
+    };
+    let at = |i, j| if by_row { v.at(i, j) } else { v.at(j, i) };
+    for i in 0..pairs {
+        let key = at(i, 0);
+        if key.is_missing_or_null() {
+            continue;
+        }
+        let key = K::from_xl_with(&key, opts)?;
+        let value = V::from_xl_with(&at(i, 1), opts)?;
+        if !insert(key, value) && opts.duplicates == Duplicates::Error {
+            return Err(ConversionError::new(format!("Duplicate key in entry {} of map", i + 1)));
+        }
+    }
+    Ok(())
+}
+
+impl<K, V, S> FromXl for HashMap<K, V, S>
+where
+    K: FromXl + Eq + Hash,
+    V: FromXl,
+    S: BuildHasher + Default,
+{
+    fn from_xl(v: &Variant) -> Result<HashMap<K, V, S>, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<HashMap<K, V, S>, ConversionError> {
+        let mut res = HashMap::default();
+        pairs_from_xl(v, opts, |k, v| res.insert(k, v).is_none())?;
+        Ok(res)
+    }
+}
+
+impl<K: FromXl + Ord, V: FromXl> FromXl for BTreeMap<K, V> {
+    fn from_xl(v: &Variant) -> Result<BTreeMap<K, V>, ConversionError> {
+        Self::from_xl_with(v, &ConvertOptions::default())
+    }
+
+    fn from_xl_with(v: &Variant, opts: &ConvertOptions) -> Result<BTreeMap<K, V>, ConversionError> {
+        let mut res = BTreeMap::new();
+        pairs_from_xl(v, opts, |k, v| res.insert(k, v).is_none())?;
+        Ok(res)
+    }
+}
+
+impl IntoXl for Variant {
+    fn into_xl(self) -> Variant {
+        self
+    }
+}
+
+impl IntoXl for f64 {
+    fn into_xl(self) -> Variant {
+        Variant::from(self)
+    }
+
+    fn vec_into_xl(mut v: Vec<f64>, columns: usize, opts: &ReturnOptions) -> Variant {
+        if v.is_empty() {
+            return XlError::NA.into_xl();
+        }
+        let columns = match limits::fit_grid(&mut v, columns, opts) {
+            Some(columns) => columns,
+            None => return XlError::Value.into_xl(),
+        };
+        if v.len() % columns != 0 {
+            return cell::grid(v.into_iter().map(Variant::from).collect(), columns, opts);
+        }
+        Variant::from(&(v.as_slice(), columns))
+    }
+
+    #[cfg(feature = "use_ndarray")]
+    fn array_into_xl(a: Array2<f64>, opts: &ReturnOptions) -> Variant {
+        match limits::fit_array(a, opts) {
+            Some(a) => Variant::from(a),
+            None => XlError::Value.into_xl(),
+        }
+    }
+}
+
+impl IntoXl for String {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        match limits::fit_text(Cow::Owned(self), opts) {
+            Some(s) => Variant::from(s.into_owned()),
+            None => XlError::Value.into_xl(),
+        }
+    }
+
+    fn vec_into_xl(mut v: Vec<String>, columns: usize, opts: &ReturnOptions) -> Variant {
+        if v.is_empty() {
+            return XlError::NA.into_xl();
+        }
+        let columns = match limits::fit_grid(&mut v, columns, opts) {
+            Some(columns) => columns,
+            None => return XlError::Value.into_xl(),
+        };
+        if v.len() % columns != 0 || limits::any_too_long(&v) {
+            let cells = v.into_iter().map(|s| s.into_xl_with(opts)).collect();
+            return cell::grid(cells, columns, opts);
+        }
+        Variant::from(&(v.iter().map(AsRef::as_ref).collect::<Vec<_>>().as_slice(), columns))
+    }
+
+    #[cfg(feature = "use_ndarray")]
+    fn array_into_xl(a: Array2<String>, opts: &ReturnOptions) -> Variant {
+        let a = match limits::fit_array(a, opts) {
+            Some(a) => a,
+            None => return XlError::Value.into_xl(),
+        };
+        if limits::any_too_long(&a) {
+            let cells = a.iter().map(|s| s.as_str().into_xl_with(opts)).collect();
+            return cell::grid(cells, a.ncols(), opts);
+        }
+        Variant::from(a)
+    }
+}
+
+impl IntoXl for &str {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        match limits::fit_text(Cow::Borrowed(self), opts) {
+            Some(s) => Variant::from(s.as_ref()),
+            None => XlError::Value.into_xl(),
+        }
+    }
+}
+
+impl IntoXl for bool {
+    fn into_xl(self) -> Variant {
+        Variant::from(self)
+    }
+}
+
+macro_rules! number_into_xl {
+    ($($t:ty),*) => {
+        $(
+            impl IntoXl for $t {
+                fn into_xl(self) -> Variant {
+                    Variant::from(self as f64)
+                }
+            }
+        )*
+    };
+}
+
+number_into_xl!(f32, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+/// `None` is shown as #N/A so that ISNA and IFNA work on the result
+impl<T: IntoXl> IntoXl for Option<T> {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        match self {
+            Some(v) => v.into_xl_with(opts),
+            None => XlError::NA.into_xl(),
+        }
+    }
+}
+
+/// Functions run for their side effects show the `unit` value of `xl_func`
+impl IntoXl for () {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        match opts.unit {
+            UnitValue::Empty => cell::empty(),
+            UnitValue::True => Variant::from(true),
+            UnitValue::Timestamp => Variant::from(now_serial()),
+        }
+    }
+}
+
+/// The current UTC time as an Excel date serial. Worked out here rather than with `xlfNow`
+/// as async functions return from a thread that can't call Excel.
+fn now_serial() -> f64 {
+    // Excel's day 0 is 30 December 1899, 25,569 days before the Unix epoch
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64());
+    25_569.0 + secs / 86_400.0
+}
+
+/// Spills down a column, or along a row with `orient = "row"`. An empty list is #N/A as
+/// Excel has no empty arrays, apart from a list of derived structs which still shows its
+/// header row.
+impl<T: XlElement> IntoXl for Vec<T> {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        let columns = match opts.orient.unwrap_or(Orient::Column) {
+            Orient::Column => 1,
+            Orient::Row => self.len(),
+        };
+        T::vec_into_xl(self, columns, opts)
+    }
+
+    /// A list of lists is a grid, one inner list per row. Short rows are padded
+    fn vec_into_xl(mut v: Vec<Vec<T>>, _columns: usize, opts: &ReturnOptions) -> Variant {
+        if !limits::fit_rows(&mut v, opts) {
+            return XlError::Value.into_xl();
+        }
+        let rows = v
+            .into_iter()
+            .map(|row| row.into_iter().map(|x| x.into_xl_with(opts)).collect())
+            .collect();
+        cell::rows(rows, opts)
+    }
+}
+
+impl<T: XlScalar> IntoXl for (Vec<T>, usize) {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        if self.0.is_empty() {
+            return XlError::NA.into_xl();
+        }
+        T::vec_into_xl(self.0, self.1, opts)
+    }
+}
+
+/// Values that fill a single cell, so can be returned side by side in a tuple
+pub trait XlScalar: IntoXl {}
+
+macro_rules! xl_scalar {
+    ($($t:ty),*) => {
+        $(impl XlScalar for $t {})*
+    };
+}
+
+xl_scalar!(f64, f32, i8, i16, i32, i64, isize, u8, u16, u32, u64, usize, bool, String, &str, XlError);
+
+impl<T: XlScalar> XlScalar for Option<T> {}
+
+/// Values that can be returned in a `Vec`: single cells, laid out as a column or row, and
+/// tuples, lists of cells and `#[derive(IntoXl)]` structs, which fill one row each. Excel
+/// can't show an array inside an array, so nothing else can be.
+pub trait XlElement: IntoXl {}
+
+impl<T: XlScalar> XlElement for T {}
+
+impl<T: XlScalar> XlElement for Vec<T> {}
+
+// Tuples spill along a row, or down a column with `orient = "column"`, each element
+// converted by its own type. A list of tuples is a table with one tuple per row.
+macro_rules! tuple_into_xl {
+    ($($n:tt $t:ident),+) => {
+        impl<$($t: XlScalar),+> IntoXl for ($($t,)+) {
+            fn into_xl(self) -> Variant {
+                self.into_xl_with(&ReturnOptions::default())
+            }
+
+            fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+                let cells = [$(self.$n.into_xl_with(opts)),+];
+                let dim = match opts.orient.unwrap_or(Orient::Row) {
+                    Orient::Row => (1, cells.len()),
+                    Orient::Column => (cells.len(), 1),
+                };
+                Variant::from_array(dim, &cells)
+            }
+
+            fn vec_into_xl(v: Vec<Self>, _columns: usize, opts: &ReturnOptions) -> Variant {
+                let mut rows = v
+                    .into_iter()
+                    .map(|row| vec![$(row.$n.into_xl_with(opts)),+])
+                    .collect();
+                if !limits::fit_rows(&mut rows, opts) {
+                    return XlError::Value.into_xl();
+                }
+                cell::rows(rows, opts)
+            }
+        }
+
+        impl<$($t: XlScalar),+> XlElement for ($($t,)+) {}
+    };
+}
+
+tuple_into_xl!(0 A);
+tuple_into_xl!(0 A, 1 B);
+tuple_into_xl!(0 A, 1 B, 2 C);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K);
+tuple_into_xl!(0 A, 1 B, 2 C, 3 D, 4 E, 5 F, 6 G, 7 H, 8 I, 9 J, 10 K, 11 L);
+
+#[cfg(feature = "use_ndarray")]
+impl<T: XlScalar + Clone> IntoXl for Array2<T> {
+    fn into_xl(self) -> Variant {
+        self.into_xl_with(&ReturnOptions::default())
+    }
+
+    fn into_xl_with(self, opts: &ReturnOptions) -> Variant {
+        T::array_into_xl(self, opts)
+    }
+}
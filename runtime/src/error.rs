@@ -0,0 +1,226 @@
+use crate::convert::{ConversionError, IntoXl};
+use crate::limits;
+use crate::options::ErrorStyle;
+use std::fmt;
+use xladd::variant::Variant;
+use xladd::xlcall::{xlerrDiv0, xlerrNA, xlerrName, xlerrNull, xlerrNum, xlerrRef, xlerrValue};
+
+/// The error values a cell can hold. Returning one of these (or a type that maps to one
+/// through [`ToXlError`]) lets ISERROR and IFERROR pick up the failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum XlError {
+    /// `#NULL!`
+    Null,
+    /// `#DIV/0!`
+    Div0,
+    /// `#VALUE!`
+    Value,
+    /// `#REF!`
+    Ref,
+    /// `#NAME?`
+    Name,
+    /// `#NUM!`
+    Num,
+    /// `#N/A`
+    NA,
+}
+
+impl XlError {
+    /// The `xlerr*` code used in an XLOPER12
+    pub fn code(self) -> u32 {
+        match self {
+            XlError::Null => xlerrNull,
+            XlError::Div0 => xlerrDiv0,
+            XlError::Value => xlerrValue,
+            XlError::Ref => xlerrRef,
+            XlError::Name => xlerrName,
+            XlError::Num => xlerrNum,
+            XlError::NA => xlerrNA,
+        }
+    }
+
+    pub fn from_code(code: u32) -> Option<XlError> {
+        [
+            XlError::Null,
+            XlError::Div0,
+            XlError::Value,
+            XlError::Ref,
+            XlError::Name,
+            XlError::Num,
+            XlError::NA,
+        ]
+        .iter()
+        .copied()
+        .find(|e| e.code() == code)
+    }
+}
+
+impl fmt::Display for XlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            XlError::Null => "#NULL!",
+            XlError::Div0 => "#DIV/0!",
+            XlError::Value => "#VALUE!",
+            XlError::Ref => "#REF!",
+            XlError::Name => "#NAME?",
+            XlError::Num => "#NUM!",
+            XlError::NA => "#N/A",
+        })
+    }
+}
+
+impl std::error::Error for XlError {}
+
+impl IntoXl for XlError {
+    fn into_xl(self) -> Variant {
+        Variant::from_err(self.code())
+    }
+}
+
+/// Maps an error returned by a function to the error value shown in the cell.
+///
+/// Error types that don't implement this show `#VALUE!`. Implement it for your own error
+/// types to pick the code, for example `#NUM!` when a solver fails to converge.
+pub trait ToXlError {
+    fn xl_error(&self) -> XlError {
+        XlError::Value
+    }
+}
+
+impl ToXlError for XlError {
+    fn xl_error(&self) -> XlError {
+        *self
+    }
+}
+
+impl ToXlError for ConversionError {}
+
+/// Boxed errors keep the code of an `XlError` that was boxed up with `?` or `.into()`
+impl ToXlError for Box<dyn std::error::Error> {
+    fn xl_error(&self) -> XlError {
+        self.downcast_ref::<XlError>().copied().unwrap_or(XlError::Value)
+    }
+}
+
+impl ToXlError for Box<dyn std::error::Error + Send + Sync> {
+    fn xl_error(&self) -> XlError {
+        self.downcast_ref::<XlError>().copied().unwrap_or(XlError::Value)
+    }
+}
+
+/// An error on its way back to Excel: the value to show in the cell and the message to log
+#[derive(Debug, Clone)]
+pub struct Failure {
+    pub code: XlError,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new<S: Into<String>>(code: XlError, message: S) -> Failure {
+        Failure {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn into_xl(self, style: ErrorStyle) -> Variant {
+        match style {
+            ErrorStyle::Code => self.code.into_xl(),
+            ErrorStyle::Text => Variant::from(limits::truncate_text(&self.message).as_str()),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Parameters that can't be converted show `#VALUE!`, as they do for Excel's own functions
+impl From<ConversionError> for Failure {
+    fn from(e: ConversionError) -> Failure {
+        Failure::new(XlError::Value, e.to_string())
+    }
+}
+
+/// Picks the error code for whatever error type a function returns. The generated code
+/// calls `(&&ErrorCode(&e)).xl_error_code()`, which resolves to [`ToXlError`] when the type
+/// implements it and falls back to `#VALUE!` for any other `Display` type.
+pub struct ErrorCode<'a, E>(pub &'a E);
+
+pub trait ViaToXlError {
+    fn xl_error_code(&self) -> XlError;
+}
+
+impl<E: ToXlError> ViaToXlError for &ErrorCode<'_, E> {
+    fn xl_error_code(&self) -> XlError {
+        self.0.xl_error()
+    }
+}
+
+pub trait ViaDisplay {
+    fn xl_error_code(&self) -> XlError;
+}
+
+impl<E: fmt::Display> ViaDisplay for ErrorCode<'_, E> {
+    fn xl_error_code(&self) -> XlError {
+        XlError::Value
+    }
+}
+
+/// Collects the messages of an error and its `source()` chain, outermost first. Like
+/// [`ErrorCode`] it is called as `(&&&&ErrorChain(&e)).error_chain()` so that any `Display`
+/// type is accepted, while `std::error::Error` types and the boxed or `anyhow` errors that
+/// dereference to one have their causes included.
+pub struct ErrorChain<'a, E>(pub &'a E);
+
+fn sources(e: &dyn std::error::Error) -> Vec<String> {
+    let mut chain = vec![e.to_string()];
+    let mut source = e.source();
+    while let Some(e) = source {
+        chain.push(e.to_string());
+        source = e.source();
+    }
+    chain
+}
+
+pub trait ChainViaError {
+    fn error_chain(&self) -> Vec<String>;
+}
+
+impl<E: std::error::Error> ChainViaError for &&&ErrorChain<'_, E> {
+    fn error_chain(&self) -> Vec<String> {
+        sources(self.0)
+    }
+}
+
+pub trait ChainViaAsRef {
+    fn error_chain(&self) -> Vec<String>;
+}
+
+impl<E: AsRef<dyn std::error::Error>> ChainViaAsRef for &&ErrorChain<'_, E> {
+    fn error_chain(&self) -> Vec<String> {
+        sources(self.0.as_ref())
+    }
+}
+
+pub trait ChainViaAsRefSend {
+    fn error_chain(&self) -> Vec<String>;
+}
+
+impl<E: AsRef<dyn std::error::Error + Send + Sync>> ChainViaAsRefSend for &ErrorChain<'_, E> {
+    fn error_chain(&self) -> Vec<String> {
+        sources(self.0.as_ref())
+    }
+}
+
+pub trait ChainViaDisplay {
+    fn error_chain(&self) -> Vec<String>;
+}
+
+impl<E: fmt::Display> ChainViaDisplay for ErrorChain<'_, E> {
+    fn error_chain(&self) -> Vec<String> {
+        vec![self.0.to_string()]
+    }
+}
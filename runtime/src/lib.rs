@@ -0,0 +1,46 @@
+//! Runtime support for the functions generated by `xladd-derive`.
+//!
+//! The `#[xl_func]` wrapper converts every Excel argument through [`FromXl`] and every
+//! returned value through [`IntoXl`]. Both traits live here rather than in the macro crate
+//! so that downstream crates can implement them for their own types.
+mod addin;
+mod cell;
+mod coerce;
+#[cfg(feature = "use_complex")]
+mod complex;
+mod convert;
+mod error;
+mod limits;
+mod memory;
+mod options;
+mod registry;
+mod table;
+
+pub use cell::XlCell;
+pub use coerce::Coerce;
+#[cfg(feature = "use_complex")]
+pub use complex::{format_complex, parse_complex};
+pub use convert::{ConversionError, FromXl, IntoXl, XlElement, XlScalar};
+pub use error::{Failure, ToXlError, XlError};
+pub use limits::{MAX_COLUMNS, MAX_ROWS, MAX_TEXT_LEN};
+pub use memory::{auto_free, enable_auto_free};
+pub use options::{
+    ConvertOptions, Duplicates, ErrorStyle, Layout, Orient, Oversize, Padding, ReturnOptions,
+    UnitValue,
+};
+pub use registry::{register_all, unregister_all, Registration};
+pub use table::{record_into_xl, table_into_xl, IntoXlRow};
+
+/// Used by the generated code
+#[doc(hidden)]
+pub mod __private {
+    pub use crate::addin::addin_manager_info;
+    pub use crate::error::{
+        ChainViaAsRef, ChainViaAsRefSend, ChainViaDisplay, ChainViaError, ErrorChain, ErrorCode,
+        ViaDisplay, ViaToXlError,
+    };
+    pub use crate::memory::{return_owned, return_thread_local};
+    pub use crate::registry::{keep_register_id, register_hidden};
+    pub use crate::table::cell;
+    pub use inventory;
+}
@@ -0,0 +1,221 @@
+use crate::options::{Oversize, ReturnOptions};
+use std::borrow::Cow;
+
+#[cfg(feature = "use_ndarray")]
+use ndarray::{s, Array2};
+
+/// Rows on a worksheet
+pub const MAX_ROWS: usize = 1_048_576;
+/// Columns on a worksheet
+pub const MAX_COLUMNS: usize = 16_384;
+/// UTF-16 code units in a cell
+pub const MAX_TEXT_LEN: usize = 32_767;
+
+/// Cuts text down to what fits in a cell, or `None` when over-long text is an error
+pub(crate) fn fit_text<'a>(s: Cow<'a, str>, opts: &ReturnOptions) -> Option<Cow<'a, str>> {
+    // Only count the UTF-16 length when the text could be too long
+    if s.len() <= MAX_TEXT_LEN || s.encode_utf16().count() <= MAX_TEXT_LEN {
+        return Some(s);
+    }
+    match opts.oversize {
+        Oversize::Error => {
+            log::error!(
+                "Text of {} characters won't fit in a cell",
+                s.chars().count()
+            );
+            None
+        }
+        Oversize::Truncate => {
+            log::warn!(
+                "Text truncated to the {} characters that fit in a cell",
+                MAX_TEXT_LEN
+            );
+            Some(Cow::Owned(truncate_text(&s)))
+        }
+    }
+}
+
+pub(crate) fn truncate_text(s: &str) -> String {
+    let mut len = 0;
+    s.chars()
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= MAX_TEXT_LEN
+        })
+        .collect()
+}
+
+/// Whether any of the values needs to go through [`fit_text`]
+pub(crate) fn any_too_long<'a, I: IntoIterator<Item = &'a String>>(values: I) -> bool {
+    values.into_iter().any(|s| s.len() > MAX_TEXT_LEN)
+}
+
+fn too_big(rows: usize, columns: usize, opts: &ReturnOptions) -> bool {
+    match opts.oversize {
+        Oversize::Error => log::error!(
+            "A result of {} rows by {} columns won't fit on a sheet of {} by {}",
+            rows,
+            columns,
+            MAX_ROWS,
+            MAX_COLUMNS
+        ),
+        Oversize::Truncate => log::warn!(
+            "A result of {} rows by {} columns truncated to fit on a sheet of {} by {}",
+            rows,
+            columns,
+            MAX_ROWS,
+            MAX_COLUMNS
+        ),
+    }
+    opts.oversize == Oversize::Truncate
+}
+
+/// Cuts `v`, laid out row by row with `columns` columns, down to what fits on a sheet.
+/// Returns the number of columns kept, or `None` when an oversized result is an error.
+pub(crate) fn fit_grid<T>(v: &mut Vec<T>, columns: usize, opts: &ReturnOptions) -> Option<usize> {
+    let columns = columns.max(1);
+    let rows = v.len().div_ceil(columns);
+    if rows <= MAX_ROWS && columns <= MAX_COLUMNS {
+        return Some(columns);
+    }
+    if !too_big(rows, columns, opts) {
+        return None;
+    }
+    let kept = columns.min(MAX_COLUMNS);
+    if kept < columns {
+        let mut col = 0;
+        v.retain(|_| {
+            let keep = col < kept;
+            col = (col + 1) % columns;
+            keep
+        });
+    }
+    v.truncate(MAX_ROWS * kept);
+    Some(kept)
+}
+
+/// Cuts a list of rows down to what fits on a sheet, or returns `false` when an oversized
+/// result is an error
+pub(crate) fn fit_rows<T>(v: &mut Vec<Vec<T>>, opts: &ReturnOptions) -> bool {
+    let columns = v.iter().map(Vec::len).max().unwrap_or(0);
+    if v.len() <= MAX_ROWS && columns <= MAX_COLUMNS {
+        return true;
+    }
+    if !too_big(v.len(), columns, opts) {
+        return false;
+    }
+    v.truncate(MAX_ROWS);
+    for row in v.iter_mut() {
+        row.truncate(MAX_COLUMNS);
+    }
+    true
+}
+
+/// Cuts an array down to what fits on a sheet, or `None` when an oversized result is an
+/// error
+#[cfg(feature = "use_ndarray")]
+pub(crate) fn fit_array<T: Clone>(a: Array2<T>, opts: &ReturnOptions) -> Option<Array2<T>> {
+    let (rows, columns) = a.dim();
+    if rows <= MAX_ROWS && columns <= MAX_COLUMNS {
+        return Some(a);
+    }
+    if !too_big(rows, columns, opts) {
+        return None;
+    }
+    Some(
+        a.slice(s![..rows.min(MAX_ROWS), ..columns.min(MAX_COLUMNS)])
+            .to_owned(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn truncate() -> ReturnOptions {
+        ReturnOptions::default().oversize(Oversize::Truncate)
+    }
+
+    #[test]
+    fn keeps_a_grid_that_fits() {
+        let mut v = vec![1, 2, 3, 4, 5, 6];
+        assert_eq!(fit_grid(&mut v, 3, &ReturnOptions::default()), Some(3));
+        assert_eq!(v, [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn drops_the_columns_beyond_the_sheet() {
+        let columns = MAX_COLUMNS + 2;
+        let mut v = (0..2 * columns).collect::<Vec<_>>();
+        assert_eq!(
+            fit_grid(&mut v.clone(), columns, &ReturnOptions::default()),
+            None
+        );
+        assert_eq!(fit_grid(&mut v, columns, &truncate()), Some(MAX_COLUMNS));
+        assert_eq!(v.len(), 2 * MAX_COLUMNS);
+        // Each row keeps its own first MAX_COLUMNS values
+        assert_eq!(v[MAX_COLUMNS - 1], MAX_COLUMNS - 1);
+        assert_eq!(v[MAX_COLUMNS], columns);
+        assert_eq!(v[2 * MAX_COLUMNS - 1], columns + MAX_COLUMNS - 1);
+    }
+
+    #[test]
+    fn drops_the_rows_beyond_the_sheet() {
+        let mut v = (0..MAX_ROWS + 3).collect::<Vec<_>>();
+        assert_eq!(fit_grid(&mut v.clone(), 1, &ReturnOptions::default()), None);
+        assert_eq!(fit_grid(&mut v, 1, &truncate()), Some(1));
+        assert_eq!(v.len(), MAX_ROWS);
+        assert_eq!(v.last(), Some(&(MAX_ROWS - 1)));
+    }
+
+    #[test]
+    fn cuts_rows_down_to_the_sheet() {
+        let mut v = vec![vec![0u8; 1]; MAX_ROWS + 1];
+        v[0] = vec![1; MAX_COLUMNS + 5];
+        assert!(!fit_rows(&mut v.clone(), &ReturnOptions::default()));
+        assert!(fit_rows(&mut v, &truncate()));
+        assert_eq!(v.len(), MAX_ROWS);
+        assert_eq!(v[0].len(), MAX_COLUMNS);
+        assert_eq!(v[1].len(), 1);
+    }
+
+    #[test]
+    fn keeps_text_up_to_the_limit() {
+        let s = "a".repeat(MAX_TEXT_LEN);
+        assert_eq!(
+            fit_text(Cow::from(s.as_str()), &ReturnOptions::default()),
+            Some(Cow::from(s.as_str()))
+        );
+        // Two bytes in UTF-8 but a single UTF-16 unit each
+        let s = "é".repeat(MAX_TEXT_LEN);
+        assert_eq!(
+            fit_text(Cow::from(s.as_str()), &ReturnOptions::default()),
+            Some(Cow::from(s.as_str()))
+        );
+        // A surrogate pair that ends exactly on the limit
+        let s = "a".repeat(MAX_TEXT_LEN - 2) + "😀";
+        assert_eq!(
+            fit_text(Cow::from(s.as_str()), &ReturnOptions::default()),
+            Some(Cow::from(s.as_str()))
+        );
+    }
+
+    #[test]
+    fn truncates_text_over_the_limit() {
+        let s = "a".repeat(MAX_TEXT_LEN + 1);
+        assert_eq!(
+            fit_text(Cow::from(s.as_str()), &ReturnOptions::default()),
+            None
+        );
+        let fitted = fit_text(Cow::from(s.as_str()), &truncate()).unwrap();
+        assert_eq!(fitted.len(), MAX_TEXT_LEN);
+    }
+
+    #[test]
+    fn never_splits_a_surrogate_pair() {
+        let s = "a".repeat(MAX_TEXT_LEN - 1) + "😀";
+        let truncated = truncate_text(&s);
+        assert_eq!(truncated, "a".repeat(MAX_TEXT_LEN - 1));
+        assert_eq!(truncated.encode_utf16().count(), MAX_TEXT_LEN - 1);
+    }
+}
@@ -0,0 +1,101 @@
+use std::cell::{RefCell, UnsafeCell};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use xladd::variant::Variant;
+use xladd::xlcall::{
+    xlbitDLLFree, xloper12__bindgen_ty_1, xltypeMulti, xltypeNil, xltypeStr, LPXLOPER12, XLOPER12,
+};
+
+thread_local! {
+    /// The last result handed to Excel from this thread. Excel copies a result before the
+    /// thread makes another call, so each call can reuse the slot.
+    static RETURN_BUFFER: UnsafeCell<XLOPER12> = const {
+        UnsafeCell::new(XLOPER12 {
+            xltype: xltypeNil,
+            val: xloper12__bindgen_ty_1 { w: 0 },
+        })
+    };
+    /// Keeps the text or array the buffer points to alive until the next result
+    static RETURN_VALUE: RefCell<Option<Variant>> = const { RefCell::new(None) };
+}
+
+/// Set once the add-in exports `xlAutoFree12`, so Excel can hand DLL-owned results back
+static AUTO_FREE: AtomicBool = AtomicBool::new(false);
+
+/// A result waiting for `xlAutoFree12`. Excel is given a copy of the variant's XLOPER12 that
+/// is owned here, so `xlbitDLLFree` can be set on it, while the variant keeps the text or
+/// array it points to alive.
+struct Returned {
+    xloper: LPXLOPER12,
+    _value: Variant,
+}
+
+impl Returned {
+    fn new(value: Variant) -> Returned {
+        let mut xloper = *value.as_xloper();
+        xloper.xltype |= xlbitDLLFree;
+        Returned {
+            xloper: Box::into_raw(Box::new(xloper)),
+            _value: value,
+        }
+    }
+}
+
+impl Drop for Returned {
+    fn drop(&mut self) {
+        // Safety: the pointer came from `Box::into_raw` and Excel has finished with it
+        drop(unsafe { Box::from_raw(self.xloper) });
+    }
+}
+
+// Safety: the value is only touched again by `auto_free`, once Excel has finished with it
+unsafe impl Send for Returned {}
+
+/// Values returned by functions that aren't thread-safe, until Excel hands them back to
+/// `xlAutoFree12`
+static RETURNED: Mutex<Vec<Returned>> = Mutex::new(Vec::new());
+
+/// Turns on DLL-owned results. Only call this from an add-in that exports an `xlAutoFree12`
+/// calling [`auto_free`], as `#[xl_addin]` does, otherwise nothing frees them.
+pub fn enable_auto_free() {
+    AUTO_FREE.store(true, Ordering::Relaxed);
+}
+
+/// Hands a result to Excel, which gives it back to `xlAutoFree12` once it has been copied.
+/// Single cells, and every result when `xlAutoFree12` isn't exported, go through the
+/// thread's return buffer instead.
+pub fn return_owned(v: Variant) -> LPXLOPER12 {
+    let xltype = v.as_xloper().xltype;
+    if !AUTO_FREE.load(Ordering::Relaxed) || xltype & (xltypeStr | xltypeMulti) == 0 {
+        return return_thread_local(v);
+    }
+    let returned = Returned::new(v);
+    let p = returned.xloper;
+    RETURNED.lock().unwrap().push(returned);
+    p
+}
+
+/// Hands a result to Excel from this thread's return buffer, releasing the previous result
+pub fn return_thread_local(v: Variant) -> LPXLOPER12 {
+    let xloper = *v.as_xloper();
+    RETURN_VALUE.with(|value| *value.borrow_mut() = Some(v));
+    RETURN_BUFFER.with(|buffer| {
+        let p = buffer.get();
+        // Safety: the buffer belongs to this thread, and Excel has copied the previous result
+        unsafe { *p = xloper };
+        p
+    })
+}
+
+/// Releases a value returned to Excel with `xlbitDLLFree` set. `#[xl_addin]` exports an
+/// `xlAutoFree12` that calls this; add-ins with a hand-written `xlAutoOpen` should export
+/// one themselves and call [`enable_auto_free`].
+pub fn auto_free(p: LPXLOPER12) {
+    let mut returned = RETURNED.lock().unwrap();
+    match returned.iter().position(|v| v.xloper == p) {
+        Some(i) => {
+            returned.swap_remove(i);
+        }
+        None => log::warn!("xlAutoFree12 was given a value this add-in didn't return"),
+    }
+}
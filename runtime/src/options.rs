@@ -0,0 +1,158 @@
+use crate::coerce::Coerce;
+
+/// What to do when a map parameter is given the same key twice
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Fail the call with a conversion error
+    Error,
+    /// Keep the value from the last row (or column) holding the key
+    Last,
+}
+
+/// How a failed call is shown in the cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorStyle {
+    /// An Excel error value such as `#VALUE!`, which ISERROR and IFERROR recognise
+    Code,
+    /// The error message as text
+    Text,
+}
+
+/// Parameter conversion settings taken from the `xl_func` attribute.
+///
+/// The generated wrapper builds one of these per call and passes it to
+/// [`FromXl::from_xl_with`](crate::FromXl::from_xl_with).
+#[derive(Debug, Clone, Copy)]
+pub struct ConvertOptions {
+    pub duplicates: Duplicates,
+    /// `None` leaves the conversion to xladd
+    pub coerce: Option<Coerce>,
+}
+
+impl Default for ConvertOptions {
+    fn default() -> ConvertOptions {
+        ConvertOptions {
+            duplicates: Duplicates::Error,
+            coerce: None,
+        }
+    }
+}
+
+impl ConvertOptions {
+    pub fn duplicates(mut self, duplicates: Duplicates) -> ConvertOptions {
+        self.duplicates = duplicates;
+        self
+    }
+
+    pub fn coerce(mut self, coerce: Coerce) -> ConvertOptions {
+        self.coerce = Some(coerce);
+        self
+    }
+}
+
+/// Which way a list is laid out when it is returned to Excel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orient {
+    Column,
+    Row,
+}
+
+/// How a struct deriving `IntoXl` is laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Labels down the first column and values down the second
+    Column,
+    /// A header row over a row of values
+    Row,
+}
+
+/// What a function returning `()` or `Result<(), E>` shows in its cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnitValue {
+    /// A blank cell
+    Empty,
+    /// `TRUE`
+    True,
+    /// The UTC time the function last ran, as an Excel date serial
+    Timestamp,
+}
+
+/// How the cells missing from a ragged 2d result are filled, e.g. the last row of a
+/// `(Vec<T>, usize)` whose length isn't a multiple of the column count
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Padding {
+    /// `#N/A`, as Excel shows for cells outside an array
+    NA,
+    /// Empty text, which looks blank
+    Blank,
+    Number(f64),
+    Text(&'static str),
+    /// The whole result is `#VALUE!`, with the shape logged
+    Error,
+}
+
+/// What happens to a result too big for a sheet, or text too long for a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Oversize {
+    /// `#VALUE!`, with the size logged
+    Error,
+    /// Keep what fits and log a warning
+    Truncate,
+}
+
+/// Return value settings taken from the `xl_func` attribute.
+///
+/// The generated wrapper passes these to [`IntoXl::into_xl_with`](crate::IntoXl::into_xl_with).
+#[derive(Debug, Clone, Copy)]
+pub struct ReturnOptions {
+    /// Layout of a returned `Vec<T>` or tuple. `None` spills lists down a column and
+    /// tuples along a row
+    pub orient: Option<Orient>,
+    /// Whether a list of records starts with a header row
+    pub headers: bool,
+    /// Shown in place of `()`
+    pub unit: UnitValue,
+    /// Fills the gaps in a ragged 2d result
+    pub padding: Padding,
+    /// Handles results beyond Excel's limits
+    pub oversize: Oversize,
+}
+
+impl Default for ReturnOptions {
+    fn default() -> ReturnOptions {
+        ReturnOptions {
+            orient: None,
+            headers: true,
+            unit: UnitValue::True,
+            padding: Padding::NA,
+            oversize: Oversize::Error,
+        }
+    }
+}
+
+impl ReturnOptions {
+    pub fn orient(mut self, orient: Orient) -> ReturnOptions {
+        self.orient = Some(orient);
+        self
+    }
+
+    pub fn headers(mut self, headers: bool) -> ReturnOptions {
+        self.headers = headers;
+        self
+    }
+
+    pub fn unit(mut self, unit: UnitValue) -> ReturnOptions {
+        self.unit = unit;
+        self
+    }
+
+    pub fn padding(mut self, padding: Padding) -> ReturnOptions {
+        self.padding = padding;
+        self
+    }
+
+    pub fn oversize(mut self, oversize: Oversize) -> ReturnOptions {
+        self.oversize = oversize;
+        self
+    }
+}
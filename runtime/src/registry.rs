@@ -0,0 +1,75 @@
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use xladd::entrypoint::excel12;
+use xladd::registrator::Reg;
+use xladd::variant::Variant;
+use xladd::xlcall::{xlGetName, xlfRegister, xlfRegisterId, xlfSetName, xlfUnregister};
+
+/// A function for [`register_all`] to register. `xl_func` submits one of these for every
+/// function it wraps.
+pub struct Registration {
+    /// The name the function is exported under
+    pub name: &'static str,
+    pub register: fn(&Reg),
+}
+
+inventory::collect!(Registration);
+
+/// The register IDs Excel gave the functions registered so far, for [`unregister_all`]
+static REGISTER_IDS: Mutex<Vec<(String, f64)>> = Mutex::new(Vec::new());
+
+/// Registers every `xl_func` in the add-in with Excel. Call it from `xlAutoOpen`.
+pub fn register_all(reg: &Reg) {
+    for registration in inventory::iter::<Registration> {
+        log::trace!("Registering {}", registration.name);
+        (registration.register)(reg);
+    }
+}
+
+/// Registers a function that can be called from cells but isn't offered in the function
+/// wizard, which is macro type 0. It gets no category or help text. Called by the generated
+/// `register_*` functions in place of `Reg::add` for `hidden` functions.
+pub fn register_hidden(_reg: &Reg, name: &str, arg_types: &str, arg_text: &str) {
+    let dll = excel12(xlGetName, &mut []);
+    excel12(
+        xlfRegister,
+        &mut [
+            dll,
+            Variant::from(name),
+            Variant::from(arg_types),
+            Variant::from(name),
+            Variant::from(arg_text),
+            Variant::from(0.0),
+        ],
+    );
+}
+
+/// Records the register ID of a function that has just been registered. Called by the
+/// generated `register_*` functions.
+pub fn keep_register_id(name: &str) {
+    let dll = excel12(xlGetName, &mut []);
+    let id = excel12(xlfRegisterId, &mut [dll, Variant::from(name)]);
+    match f64::try_from(&id) {
+        Ok(id) => {
+            // Excel calls xlAutoOpen again when the add-in is reopened, so a name can come
+            // round twice
+            let mut ids = REGISTER_IDS.lock().unwrap();
+            match ids.iter_mut().find(|(n, _)| n == name) {
+                Some(entry) => entry.1 = id,
+                None => ids.push((name.to_string(), id)),
+            }
+        }
+        Err(_) => log::warn!("Excel has no register ID for {}", name),
+    }
+}
+
+/// Unregisters every function registered so far. Call it from `xlAutoClose`.
+pub fn unregister_all() {
+    let mut ids = REGISTER_IDS.lock().unwrap();
+    for (name, id) in ids.drain(..) {
+        log::trace!("Unregistering {}", name);
+        excel12(xlfUnregister, &mut [Variant::from(id)]);
+        // Unregistering leaves the name behind in the function wizard
+        excel12(xlfSetName, &mut [Variant::from(name.as_str())]);
+    }
+}
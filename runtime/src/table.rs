@@ -0,0 +1,62 @@
+use crate::convert::{IntoXl, XlScalar};
+use crate::error::XlError;
+use crate::limits;
+use crate::options::{Layout, ReturnOptions};
+use xladd::variant::Variant;
+
+/// A struct whose fields fill one row of cells under a row of headers. Derived along with
+/// `IntoXl` by `#[derive(IntoXl)]`.
+pub trait IntoXlRow {
+    fn headers() -> Vec<&'static str>;
+    fn into_row(self, opts: &ReturnOptions) -> Vec<Variant>;
+}
+
+/// Lays out a single record as labelled values
+pub fn record_into_xl<T: IntoXlRow>(v: T, layout: Layout, opts: &ReturnOptions) -> Variant {
+    let headers = T::headers();
+    let values = v.into_row(opts);
+    if values.is_empty() {
+        return XlError::NA.into_xl();
+    }
+    match layout {
+        Layout::Column => {
+            let cells = headers
+                .into_iter()
+                .zip(values)
+                .flat_map(|(header, value)| vec![Variant::from(header), value])
+                .collect::<Vec<_>>();
+            Variant::from_array((cells.len() / 2, 2), &cells)
+        }
+        Layout::Row => {
+            let columns = values.len();
+            let cells = headers.into_iter().map(Variant::from).chain(values).collect::<Vec<_>>();
+            Variant::from_array((2, columns), &cells)
+        }
+    }
+}
+
+/// Lays out a list of records as a table, one row per record under a header row. The
+/// header row is left out with `headers = "false"`.
+pub fn table_into_xl<T: IntoXlRow>(rows: Vec<T>, opts: &ReturnOptions) -> Variant {
+    let columns = T::headers().len();
+    if columns == 0 || (rows.is_empty() && !opts.headers) {
+        return XlError::NA.into_xl();
+    }
+    let mut cells = Vec::with_capacity((rows.len() + 1) * columns);
+    if opts.headers {
+        cells.extend(T::headers().into_iter().map(Variant::from));
+    }
+    for row in rows {
+        cells.extend(row.into_row(opts));
+    }
+    let columns = match limits::fit_grid(&mut cells, columns, opts) {
+        Some(columns) => columns,
+        None => return XlError::Value.into_xl(),
+    };
+    Variant::from_array((cells.len() / columns, columns), &cells)
+}
+
+/// Converts one field of a record, which has to fit in a single cell
+pub fn cell<T: XlScalar>(v: T, opts: &ReturnOptions) -> Variant {
+    v.into_xl_with(opts)
+}
@@ -0,0 +1,78 @@
+use quote::quote;
+use std::collections::BTreeMap;
+
+/// Generates the XLL lifecycle exports around the setup function `item`
+pub fn expand(params: &BTreeMap<String, String>, item: &syn::ItemFn) -> proc_macro2::TokenStream {
+    if !item.sig.inputs.is_empty() {
+        panic!("The xl_addin setup function can't take any arguments");
+    }
+    let setup = &item.sig.ident;
+    let name = match params.get("name") {
+        Some(name) => quote!(#name),
+        None => quote!(env!("CARGO_PKG_NAME")),
+    };
+    let version = match params.get("version") {
+        Some(version) => quote!(#version),
+        None => quote!(env!("CARGO_PKG_VERSION")),
+    };
+    let title = quote!(concat!(#name, " ", #version));
+    // A setup function returning a Result can stop the add-in from loading
+    let call_setup = match item.sig.output {
+        syn::ReturnType::Default => quote!(#setup();),
+        syn::ReturnType::Type(..) => quote! {
+            if let Err(e) = #setup() {
+                log::error!("{} failed to load: {}", #title, e);
+                return 0;
+            }
+        },
+    };
+
+    quote! {
+        #item
+
+        // Called by Excel when the add-in is loaded
+        #[no_mangle]
+        pub extern "stdcall" fn xlAutoOpen() -> i32 {
+            // Results can be DLL owned as xlAutoFree12 is exported below
+            xladd_derive_runtime::enable_auto_free();
+            #call_setup
+            let reg = xladd::registrator::Reg::new();
+            xladd_derive_runtime::register_all(&reg);
+            log::info!("{} loaded", #title);
+            1 // Must return 1 to signal to excel SUCCESS
+        }
+
+        #[no_mangle]
+        pub extern "stdcall" fn xlAutoClose() -> i32 {
+            xladd_derive_runtime::unregister_all();
+            log::info!("{} closed", #title);
+            1
+        }
+
+        // Called when the add-in is ticked in the Add-In Manager
+        #[no_mangle]
+        pub extern "stdcall" fn xlAutoAdd() -> i32 {
+            1
+        }
+
+        // Called when the add-in is unticked in the Add-In Manager
+        #[no_mangle]
+        pub extern "stdcall" fn xlAutoRemove() -> i32 {
+            1
+        }
+
+        // Excel hands back every result once it has copied it
+        #[no_mangle]
+        pub extern "stdcall" fn xlAutoFree12(p: xladd::xlcall::LPXLOPER12) {
+            xladd_derive_runtime::auto_free(p);
+        }
+
+        // The name shown in the Add-In Manager
+        #[no_mangle]
+        pub extern "stdcall" fn xlAddInManagerInfo12(
+            action: xladd::xlcall::LPXLOPER12,
+        ) -> xladd::xlcall::LPXLOPER12 {
+            xladd_derive_runtime::__private::addin_manager_info(action, #title)
+        }
+    }
+}
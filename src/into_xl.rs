@@ -0,0 +1,73 @@
+use crate::xl_options;
+use quote::quote;
+
+/// Generates `IntoXlRow` and `IntoXl` for a struct with named fields. Each field that isn't
+/// skipped becomes one cell, labelled with its name or its rename. A `Vec` of the struct
+/// becomes a table with one row per element.
+pub fn derive(item: &syn::DeriveInput) -> proc_macro2::TokenStream {
+    let name = &item.ident;
+    let (impl_generics, ty_generics, where_clause) = item.generics.split_for_impl();
+    let fields = match &item.data {
+        syn::Data::Struct(syn::DataStruct {
+            fields: syn::Fields::Named(fields),
+            ..
+        }) => &fields.named,
+        _ => panic!("IntoXl can only be derived for structs with named fields"),
+    };
+    let layout = match xl_options(&item.attrs).get("layout").map(String::as_str) {
+        None | Some("column") => quote!(xladd_derive_runtime::Layout::Column),
+        Some("row") => quote!(xladd_derive_runtime::Layout::Row),
+        Some(v) => panic!("layout must be \"column\" or \"row\", not {}", v),
+    };
+
+    let (headers, cells): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .filter_map(|field| {
+            let options = xl_options(&field.attrs);
+            if options.contains_key("skip") {
+                return None;
+            }
+            let ident = field.ident.as_ref().unwrap();
+            let header = match options.get("rename") {
+                Some(rename) => rename.clone(),
+                None => ident.to_string(),
+            };
+            Some((header, quote!(xladd_derive_runtime::__private::cell(self.#ident, opts))))
+        })
+        .unzip();
+
+    quote! {
+        impl #impl_generics xladd_derive_runtime::IntoXlRow for #name #ty_generics #where_clause {
+            fn headers() -> Vec<&'static str> {
+                vec![#(#headers),*]
+            }
+
+            fn into_row(
+                self,
+                opts: &xladd_derive_runtime::ReturnOptions,
+            ) -> Vec<xladd::variant::Variant> {
+                vec![#(#cells),*]
+            }
+        }
+
+        impl #impl_generics xladd_derive_runtime::IntoXl for #name #ty_generics #where_clause {
+            fn into_xl(self) -> xladd::variant::Variant {
+                self.into_xl_with(&xladd_derive_runtime::ReturnOptions::default())
+            }
+
+            fn into_xl_with(self, opts: &xladd_derive_runtime::ReturnOptions) -> xladd::variant::Variant {
+                xladd_derive_runtime::record_into_xl(self, #layout, opts)
+            }
+
+            fn vec_into_xl(
+                v: Vec<Self>,
+                _columns: usize,
+                opts: &xladd_derive_runtime::ReturnOptions,
+            ) -> xladd::variant::Variant {
+                xladd_derive_runtime::table_into_xl(v, opts)
+            }
+        }
+
+        impl #impl_generics xladd_derive_runtime::XlElement for #name #ty_generics #where_clause {}
+    }
+}
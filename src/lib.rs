@@ -6,6 +6,29 @@ use syn::{FnArg, ItemFn};
 
 
 
+mod addin;
+mod into_xl;
+mod manifest;
+
+/// Generates the exports Excel looks for in an XLL: `xlAutoOpen`, `xlAutoClose`, `xlAutoAdd`,
+/// `xlAutoRemove` and `xlAddInManagerInfo12`. Put it on a setup function, which is called
+/// from `xlAutoOpen` before every `xl_func` is registered. Takes `name` and `version`, which
+/// default to the crate's.
+#[proc_macro_attribute]
+pub fn xl_addin(attr: TokenStream, input: TokenStream) -> TokenStream {
+    let item = syn::parse::<ItemFn>(input).expect("Failed to parse.");
+    addin::expand(&attr_params(attr), &item).into()
+}
+
+/// Implements `IntoXl` for a struct so that it can be returned from an `xl_func`, as a two
+/// column block of labels and values or, with `#[xl(layout = "row")]`, as a header row over
+/// a row of values. Fields take `#[xl(rename = "Label")]` and `#[xl(skip)]`.
+#[proc_macro_derive(IntoXl, attributes(xl))]
+pub fn derive_into_xl(input: TokenStream) -> TokenStream {
+    let item = syn::parse::<syn::DeriveInput>(input).expect("Failed to parse.");
+    into_xl::derive(&item).into()
+}
+
 #[proc_macro_attribute]
 pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
     // println!("{:?}", attr);
@@ -13,23 +36,80 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
     // println!("{:?}", input);
 
     let item = syn::parse::<ItemFn>(input).expect("Failed to parse.");
-    let mut params = BTreeMap::new();
-    let tree = attr.into_iter().collect::<Vec<TokenTree>>();
-    for chunk in tree.as_slice().windows(3) {
-        match chunk {
-            [TokenTree::Ident(i),TokenTree::Punct(_),TokenTree::Literal(l)] => {
-                let l = l.to_string();
-                params.insert(i.to_string(),l[1..l.len()-1].to_string());
-            },
-            _ => (),
-        }
-    }
+    let aliases = attr_list(attr.clone(), "aliases");
+    let register_if = attr_path(attr.clone(), "register_if");
+    let params = attr_params(attr);
     let name = item.sig.ident.to_string();
     let category = if let Some(v) = params.get("category") { v } else { "" };
     let prefix = if let Some(v) = params.get("prefix") { v } else { "xl" };
     let rename = if let Some(v) = params.get("rename") { v } else { name.as_str() };
     let async_function = if let Some(_) = params.get("async") { true } else { false };
-    let single_threaded = if let Some(_) = params.get("single_threaded") { true } else { true };
+    let hidden = match params.get("hidden").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(v) => panic!("hidden must be \"true\" or \"false\", not {}", v),
+    };
+    let deprecate_aliases = match params.get("deprecate_aliases").map(String::as_str) {
+        None | Some("false") => false,
+        Some("true") => true,
+        Some(v) => panic!("deprecate_aliases must be \"true\" or \"false\", not {}", v),
+    };
+    let single_threaded = match params.get("thread_safe").map(String::as_str) {
+        None | Some("false") => true,
+        Some("true") => false,
+        Some(v) => panic!("thread_safe must be \"true\" or \"false\", not {}", v),
+    };
+    let duplicate_keys = match params.get("duplicate_keys").map(String::as_str) {
+        None | Some("error") => quote!(xladd_derive_runtime::Duplicates::Error),
+        Some("last") => quote!(xladd_derive_runtime::Duplicates::Last),
+        Some(v) => panic!("duplicate_keys must be \"error\" or \"last\", not {}", v),
+    };
+    let coerce = params.get("coerce").map(|v| coerce_policy(v));
+    let error_style = match params.get("errors").map(String::as_str) {
+        None | Some("code") => quote!(xladd_derive_runtime::ErrorStyle::Code),
+        Some("text") => quote!(xladd_derive_runtime::ErrorStyle::Text),
+        Some(v) => panic!("errors must be \"code\" or \"text\", not {}", v),
+    };
+    // Lists default to a column and tuples to a row
+    let orient = match params.get("orient").map(String::as_str) {
+        None => quote!(),
+        Some("column") => quote!(.orient(xladd_derive_runtime::Orient::Column)),
+        Some("row") => quote!(.orient(xladd_derive_runtime::Orient::Row)),
+        Some(v) => panic!("orient must be \"column\" or \"row\", not {}", v),
+    };
+    // What a function returning () shows in its cell
+    let unit = match params.get("unit").map(String::as_str) {
+        None | Some("true") => quote!(),
+        Some("empty") => quote!(.unit(xladd_derive_runtime::UnitValue::Empty)),
+        Some("timestamp") => quote!(.unit(xladd_derive_runtime::UnitValue::Timestamp)),
+        Some(v) => panic!("unit must be \"empty\", \"true\" or \"timestamp\", not {}", v),
+    };
+    // Lists of records start with a header row unless turned off
+    let headers = match params.get("headers").map(String::as_str) {
+        None | Some("true") => quote!(),
+        Some("false") => quote!(.headers(false)),
+        Some(v) => panic!("headers must be \"true\" or \"false\", not {}", v),
+    };
+    // Gaps in ragged 2d results are #N/A unless told otherwise
+    let padding = match (params.get("pad").map(String::as_str), params.get("pad_value")) {
+        (None, None) | (Some("na"), None) => quote!(),
+        (Some("blank"), None) => quote!(.padding(xladd_derive_runtime::Padding::Blank)),
+        (Some("error"), None) => quote!(.padding(xladd_derive_runtime::Padding::Error)),
+        // "nan" or "inf" parse as numbers but a cell can't hold them, so they stay text
+        (None, Some(v)) => match v.parse::<f64>().ok().filter(|n| n.is_finite()) {
+            Some(n) => quote!(.padding(xladd_derive_runtime::Padding::Number(#n))),
+            None => quote!(.padding(xladd_derive_runtime::Padding::Text(#v))),
+        },
+        (Some(_), Some(_)) => panic!("Use either pad or pad_value, not both"),
+        (Some(v), None) => panic!("pad must be \"na\", \"blank\" or \"error\", not {}", v),
+    };
+    // Results beyond the size of a sheet are an error unless they may be cut down
+    let oversize = match params.get("oversize").map(String::as_str) {
+        None | Some("error") => quote!(),
+        Some("truncate") => quote!(.oversize(xladd_derive_runtime::Oversize::Truncate)),
+        Some(v) => panic!("oversize must be \"error\" or \"truncate\", not {}", v),
+    };
+    let return_options = quote!(xladd_derive_runtime::ReturnOptions::default()#orient#headers#unit#padding#oversize);
     // Use `quote` to convert the syntax tree back into tokens so we can return them. Note
     // that the tokens we're returning at this point are still just the input, we've simply
     // converted it between a few different forms.
@@ -49,10 +129,9 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
         &format!("register_{}", func),
         proc_macro2::Span::call_site(),
     );
-    // From the signature, identify the types we handle
-    // f32,f64,i32,i64,bool,&str,&[&str],&[f64]
-    // and map them to the corresponding owned types
-
+    // From the signature, identify the types we handle and map them to an owned type
+    // that is converted with FromXl, plus the statements that turn that back into the
+    // declared type (&str, &[&str], &[f64], Cow<str>, Box<[f64]> etc.)
     let typed_args = &item.sig.inputs.iter().map(|arg| match arg {
         FnArg::Typed(typed_arg) => {
             // Arg name
@@ -63,175 +142,28 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
                     _ => panic!("Type not covered"),
                 }
             };
-            // Owned type
-            let owned_type = {
-                let ty = &typed_arg.ty;
-                match &**ty {
-                    syn::Type::Path(p) => {
-                        let segment = &p.path.segments[0];
-                        let ident = &segment.ident;
-                        let p_type = if ident == "str" {
-                            quote!(String)
-                        } else {
-                            quote!(#p)
-                        };
-                        quote!( 
-                            if #arg_name.is_missing_or_null() {
-                                //return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
-                            }
-                            let #arg_name = std::convert::TryInto::<#p_type>::try_into(&#arg_name)?; 
-                            log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
-                        )
-                    }
-                    syn::Type::Reference(p) => {
-                        let elem = &p.elem;
-                        // Slice
-                        match &**elem {
-                            syn::Type::Slice(s) => {
-                                let elem = &s.elem;
-                                match &**elem {
-                                    syn::Type::Path(p) => {
-                                        let segment = &p.path.segments[0];
-                                        let ident = &segment.ident;
-                                        quote!( 
-                                                if #arg_name.is_missing_or_null() {
-                                                    //return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
-                                                }
-                                                let #arg_name = std::convert::TryInto::<Vec<#ident>>::try_into(&#arg_name)?;
-                                                log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
-                                                //let #arg_name = #arg_name.as_slice();
-                                        )
-                                    }
-                                    syn::Type::Reference(p) => {
-                                        let elem = &p.elem;
-                                        match &**elem {
-                                            syn::Type::Path(p) => {
-                                                let segment = &p.path.segments[0];
-                                                let ident = &segment.ident;
-                                                if ident == "str" {
-                                                    quote!( if #arg_name.is_missing_or_null() {
-                                                                //return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
-                                                            }
-                                                            let #arg_name = std::convert::TryInto::<Vec<String>>::try_into(&#arg_name)?;
-                                                            let #arg_name = #arg_name.iter().map(AsRef::as_ref).collect::<Vec<_>>();
-                                                            log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
-                                                            //let #arg_name = #arg_name.as_slice();
-                                                    )
-                                                } else {
-                                                    panic!("Only slices of &[&str] supported")
-                                                }
-                                            }
-                                            _ => panic!("Type not covered"),
-                                        }
-                                    }
-                                    _ => panic!("Type not covered"),
-                                }
-                            }
-                            syn::Type::Path(s) => {
-                                let segment = &s.path.segments[0];
-                                let ident = &segment.ident;
-                                if ident == "str" { 
-                                    quote!(
-                                        if #arg_name.is_missing_or_null() {
-                                            //return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
-                                        }
-                                        let #arg_name = std::convert::TryInto::<String>::try_into(&#arg_name)?;
-                                        log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
-                                       // let #arg_name = #arg_name.as_str();
-                                    )
-                                } else { 
-                                    quote!(
-                                        if #arg_name.is_missing_or_null() {
-                                            // return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
-                                        }
-                                        let #arg_name = std::convert::TryInto::<#ident>::try_into(&#arg_name)?;
-                                        log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
-                                        )
-                                    }
-                            }
-                            _ => panic!("Type not covered"),
-                        }
-
-                        // or Path
-                    }
-                    _ => panic!("Type not covered"),
+            let (owned_type, reference_type) = param_conversion(&arg_name, &typed_arg.ty);
+            // #[xl(coerce = "...")] on the parameter overrides the function wide policy
+            let arg_params = xl_options(&typed_arg.attrs);
+            let arg_options = match arg_params.get("coerce") {
+                Some(v) => {
+                    let coerce = coerce_policy(v);
+                    quote!(&convert_options.coerce(#coerce))
                 }
+                None => quote!(&convert_options),
             };
-            // Slice converted for &[&str],&[f64]
-            (arg_name, owned_type)
-        }
-        FnArg::Receiver(_) => panic!("Free functions only"),
-    });
-
-
-    // Rerefence args
-    let reference_args = &item.sig.inputs.iter().map(|arg| match arg {
-        FnArg::Typed(typed_arg) => {
-            // Arg name
-            let arg_name = {
-                let pat = &typed_arg.pat;
-                match &**pat {
-                    syn::Pat::Ident(ident) => quote!(#ident),
-                    _ => panic!("Type not covered"),
-                }
-            };
-            // Owned type
-            let owned_type = {
-                let ty = &typed_arg.ty;
-                match &**ty {
-                    syn::Type::Path(_) => {
-                        quote!()
-                    }
-                    syn::Type::Reference(p) => {
-                        let elem = &p.elem;
-                        // Slice
-                        match &**elem {
-                            syn::Type::Slice(s) => {
-                                let elem = &s.elem;
-                                match &**elem {
-                                    syn::Type::Path(_) => {
-                                        quote!( let #arg_name = #arg_name.as_slice(); )
-                                    }
-                                    syn::Type::Reference(p) => {
-                                        let elem = &p.elem;
-                                        match &**elem {
-                                            syn::Type::Path(p) => {
-                                                let segment = &p.path.segments[0];
-                                                let ident = &segment.ident;
-                                                if ident == "str" {
-                                                    quote!( let #arg_name = #arg_name.as_slice(); )
-                                                } else {
-                                                    panic!("Only slices of &[&str] supported")
-                                                }
-                                            }
-                                            _ => panic!("Type not covered"),
-                                        }
-                                    }
-                                    _ => panic!("Type not covered"),
-                                }
-                            }
-                            syn::Type::Path(s) => {
-                                let segment = &s.path.segments[0];
-                                let ident = &segment.ident;
-                                if ident == "str" { 
-                                    quote!(let #arg_name = #arg_name.as_str(); )
-                                } else { 
-                                    quote!()
-                                    }
-                            }
-                            _ => panic!("Type not covered"),
-                        }
-
-                        // or Path
-                    }
-                    _ => panic!("Type not covered"),
+            let owned_type = quote!(
+                if #arg_name.is_missing_or_null() {
+                    //return Err(Box::new(xladd::variant::XLAddError::MissingArgument(stringify!(#func).to_string(),stringify!(#arg_name).to_string())));
                 }
-            };
-            // Slice converted for &[&str],&[f64]
-            (arg_name, owned_type)
+                let #arg_name = <#owned_type as xladd_derive_runtime::FromXl>::from_xl_with(&#arg_name, #arg_options)?;
+                log::trace!("{}:[{:?}]",stringify!(#arg_name),#arg_name);
+            );
+            (arg_name, owned_type, reference_type)
         }
         FnArg::Receiver(_) => panic!("Free functions only"),
     });
+
     // Parse the doc comments
 
     let comments = &item.attrs.iter().filter_map(|attr: &syn::Attribute| {
@@ -242,20 +174,20 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
             None
         }
     });
-    let args = typed_args
+    let arg_docs = typed_args
         .clone()
-        .filter_map(|(name, _)| {
+        .filter_map(|(name, _, _)| {
             let name = name.to_string();
             comments.clone().find_map(|v| {
                 if v.starts_with(&format!("= \" * {} -", name)) {
-                    let v = &v[name.len() + 9..v.len() - 1];
-                    Some(quote! {#v})
+                    Some((name.clone(), v[name.len() + 9..v.len() - 1].to_string()))
                 } else {
                     None
                 }
             })
         })
         .collect::<Vec<_>>();
+    let args = arg_docs.iter().map(|(_, v)| quote! {#v}).collect::<Vec<_>>();
     let ret = comments.clone().find_map(|v| {
         if v.starts_with("= \" * ret -") {
             let v = &v[12..v.len() - 1];
@@ -286,109 +218,93 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
         },
     ]
     .join(" and ");
-    // Return type convert back to variant
-    let output = {
+    // Return type convert back to variant. Functions that don't return a Result are wrapped
+    // in an infallible Ok so that both are called the same way
+    let into_xl = quote! {Ok(xladd_derive_runtime::IntoXl::into_xl_with(res, &#return_options))};
+    let (output, returns_result) = {
         match output {
-            syn::ReturnType::Default => quote! {},
-            syn::ReturnType::Type(_, path) => match &**path {
-                syn::Type::Path(path) => {
-                    let segment = &path.path.segments[0];
-                    if segment.ident == "Result" {
-                        let args = &segment.arguments;
-                        match args {
-                            syn::PathArguments::AngleBracketed(generic_args) => {
-                                let arg0 = &generic_args.args[0];
-                                match &*arg0 {
-                                    syn::GenericArgument::Type(path) => match path {
-                                        syn::Type::Tuple(tuple) => {
-                                            let elems = &tuple.elems[0];
-                                            match &*elems {
-                                                syn::Type::Path(path) => {
-                                                    let segment = &path.path.segments[0];
-                                                    if segment.ident == "Vec" {
-                                                        let args = &segment.arguments;
-                                                        match &*args {
-                                                            syn::PathArguments::AngleBracketed(generic_args) => {
-                                                                let arg0 = &generic_args.args[0];
-                                                                match &*arg0 {
-                                                                    syn::GenericArgument::Type(path) => {
-                                                                        match path {
-                                                                            syn::Type::Path(p) => {
-                                                                                if p.path.segments[0].ident == "String" {
-                                                                                    quote! {Ok(Variant::from(&(res.0.iter().map(AsRef::as_ref).collect::<Vec<_>>().as_slice(),res.1)))}
-                                                                                } else {
-                                                                                    quote! {Ok(Variant::from(&(res.0.as_slice(),res.1)))}
-                                                                                }
-                                                                            },
-                                                                            _ => panic!("Expected a type of f64,u32,bool,String")
-                                                                        }
- 
-                                                                    },
-                                                                    _ =>  panic!("Expected a simple type after a vec"),
-
-                                                                }
-                                                            },
-                                                                syn::PathArguments::Parenthesized(_) => {
-                                                                    quote! {Ok(Variant::from(true))}
-                                                                },
-                                                                syn::PathArguments::None => panic!("Unhandled type for result0"),
-                                                        }
-//                                                        quote! {Ok(Variant::from(&(res.0.as_slice(),res.1)))}
-                                                    } else {
-                                                        quote! {Ok(Variant::from(res))}
-                                                    }        
-                                                },
-                                                _ => panic!("Tuple returned must of <Vec<f64>,Dimension(usize)>")
-
-                                            }
-                                        }
-                                        syn::Type::Path(_) => {
-                                            quote! {Ok(Variant::from(res))}
-                                        },
-                                        _ => panic!("XL functions must return a basic type of f64,i64,u32,i32,bool or a tuple of (Vec<f64>,Dimension(usize))")
-                                    },
-                                    _ => panic!("XL functions must return a basic type of f64,i64,u32,i32,bool or a tuple of (Vec<f64>,Dimension(usize))")
-                                }
+            // Side effect only functions show the `unit` value
+            syn::ReturnType::Default => (into_xl.clone(), false),
+            syn::ReturnType::Type(_, ty) => match &**ty {
+                // `Result`, `std::result::Result` or an alias such as `anyhow::Result`
+                syn::Type::Path(path) if path.path.segments.last().unwrap().ident == "Result" => {
+                    let segment = path.path.segments.last().unwrap();
+                    let args = &segment.arguments;
+                    let output = match args {
+                        syn::PathArguments::AngleBracketed(generic_args) => {
+                            let arg0 = &generic_args.args[0];
+                            match &*arg0 {
+                                // Any type implementing IntoXl, including Option<T>, Vec<T>, tuples of scalars, the (Vec<T>,usize) tuple and ()
+                                syn::GenericArgument::Type(syn::Type::Path(_))
+                                | syn::GenericArgument::Type(syn::Type::Tuple(_))
+                                | syn::GenericArgument::Type(syn::Type::Reference(_)) => into_xl.clone(),
+                                _ => panic!("XL functions must return a type implementing xladd_derive_runtime::IntoXl or a tuple of (Vec<T>,Dimension(usize))")
                             }
-                            syn::PathArguments::Parenthesized(_) => {
-                                quote! {Ok(Variant::from(true))}
-                            }
-                            syn::PathArguments::None => quote! {} //panic!("XL functions must return a basic type of f64,i64,u32,i32,bool or a tuple of (Vec<f64>,Dimension(usize))")
-
                         }
-                    } else {
-                        panic!("XL functions must return a Result<TYPE,Error>. Error can be coerced into a Box<std::error::Error>")
-                    }
+                        _ => panic!("XL functions must return a Result<TYPE,Error>, where TYPE implements xladd_derive_runtime::IntoXl"),
+                    };
+                    (output, true)
                 }
-                _ => panic!("Unhandled type"),
+                // Anything else that converts, e.g. `f64` or `Option<T>` where None is returned as #N/A
+                syn::Type::Path(_) | syn::Type::Tuple(_) | syn::Type::Reference(_) => (into_xl, false),
+                _ => panic!("XL functions must return a type implementing xladd_derive_runtime::IntoXl, optionally wrapped in a Result"),
             },
         }
     };
+    // What a panic in the function or its conversions is shown as
+    let panicked = quote! {{
+        log::error!("Unexpected error while calling function");
+        xladd_derive_runtime::Failure::new(
+            xladd_derive_runtime::XlError::Value,
+            "Error when trying to execute function, check for invalid values, ranges, or #n/a",
+        )
+    }};
     // Now collate
     let lpx_oper_args = typed_args
         .clone()
-        .map(|(name, _)| quote!(#name: LPXLOPER12))
+        .map(|(name, _, _)| quote!(#name: LPXLOPER12))
         .collect::<Vec<_>>();
     let variant_args = typed_args
         .clone()
-        .map(|(name, _)| quote!(#name: xladd::variant::Variant))
+        .map(|(name, _, _)| quote!(#name: xladd::variant::Variant))
         .collect::<Vec<_>>();
     let to_variant = typed_args
         .clone()
-        .map(|(name, _)| quote!(let #name = xladd::variant::Variant::from(#name);))
+        .map(|(name, _, _)| quote!(let #name = xladd::variant::Variant::from(#name);))
         .collect::<Vec<_>>();
     let caller_args = typed_args
         .clone()
-        .map(|(name, _)| quote!(#name))
+        .map(|(name, _, _)| quote!(#name))
         .collect::<Vec<_>>();
+    let call = if returns_result {
+        quote!(#func(#(#caller_args),*))
+    } else {
+        quote!(Ok::<_, std::convert::Infallible>(#func(#(#caller_args),*)))
+    };
+    // Errors returned by the function are mapped to a cell error through ToXlError when the
+    // error type implements it, and to #VALUE! otherwise
+    let to_failure = quote!(|e| {
+        #[allow(unused_imports)]
+        use xladd_derive_runtime::__private::{
+            ChainViaAsRef as _, ChainViaAsRefSend as _, ChainViaDisplay as _, ChainViaError as _,
+            ViaDisplay as _, ViaToXlError as _,
+        };
+        let code = (&&xladd_derive_runtime::__private::ErrorCode(&e)).xl_error_code();
+        // The cell only gets the outermost message, the causes go to the log
+        let chain = (&&&&xladd_derive_runtime::__private::ErrorChain(&e)).error_chain();
+        if chain.len() > 1 {
+            log::error!("{} failed: {}", stringify!(#xl_function), chain.join(": "));
+        }
+        xladd_derive_runtime::Failure::new(code, e.to_string())
+    });
     let caller_args_str = typed_args
         .clone()
-        .map(|(name, _)| name.to_string())
+        .map(|(name, _, _)| name.to_string())
         .collect::<Vec<_>>()
         .join(",");
     let mut q_args = typed_args
         .clone()
-        .map(|(_, _)| "Q")
+        .map(|(_, _, _)| "Q")
         .collect::<Vec<_>>()
         .join("");
     // Mark function as async
@@ -400,46 +316,203 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
         q_args.push('Q');
     }
     // not_thread_safe
-    if !(single_threaded || async_function) { q_args.push('$'); };
+    let thread_safe = !(single_threaded || async_function);
+    if thread_safe { q_args.push('$'); };
+    // Thread-safe functions reuse a return buffer per thread. The rest hand Excel DLL owned
+    // results, kept until Excel passes them to xlAutoFree12
+    let return_value = if thread_safe {
+        quote!(xladd_derive_runtime::__private::return_thread_local)
+    } else {
+        quote!(xladd_derive_runtime::__private::return_owned)
+    };
     let convert_to_owned_rust_types = typed_args
         .clone()
-        .map(|(_, owned_type)| owned_type)
+        .map(|(_, owned_type, _)| owned_type)
         .collect::<Vec<_>>();
 
-    let convert_to_ref_rust_types = reference_args
+    let convert_to_ref_rust_types = typed_args
         .clone()
-        .map(|(_, owned_type)| owned_type)
+        .map(|(_, _, reference_type)| reference_type)
         .collect::<Vec<_>>();
 
+    let convert_options = if item.sig.inputs.is_empty() {
+        quote!()
+    } else {
+        let coerce = coerce.map(|coerce| quote!(.coerce(#coerce)));
+        quote!(let convert_options = xladd_derive_runtime::ConvertOptions::default().duplicates(#duplicate_keys)#coerce;)
+    };
+    // A #[cfg] below #[xl_func] has to remove the generated items along with the function
+    let cfg_attrs = item
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("cfg"))
+        .collect::<Vec<_>>();
+    // The #[xl(...)] parameter attributes are only meaningful to this macro
+    let mut user_item = item.clone();
+    for arg in user_item.sig.inputs.iter_mut() {
+        if let FnArg::Typed(typed_arg) = arg {
+            typed_arg.attrs.retain(|attr| !attr.path.is_ident("xl"));
+        }
+    }
         let xl_function_str = xl_function.to_string();
+    // Old names for the function, exported under the same prefix
+    let alias_functions = aliases
+        .iter()
+        .map(|alias| proc_macro2::Ident::new(&format!("{}_{}", prefix, alias), proc_macro2::Span::call_site()))
+        .collect::<Vec<_>>();
+    let alias_docs = if deprecate_aliases {
+        format!("{} (deprecated, use {})", docs_ret, xl_function_str).trim().to_string()
+    } else {
+        docs_ret.clone()
+    };
+    // Hidden functions are left out of the function wizard, so get no documentation
+    let registrations = std::iter::once((xl_function_str.clone(), docs_ret.clone()))
+        .chain(alias_functions.iter().map(|alias| (alias.to_string(), alias_docs.clone())))
+        .map(|(name, docs)| {
+            let registration = if hidden {
+                quote!(xladd_derive_runtime::__private::register_hidden(reg, #name, #q_args, #caller_args_str);)
+            } else {
+                quote!(reg.add(#name,#q_args,#caller_args_str,#category,#docs,&[#(#args),*]);)
+            };
+            quote! {
+                #registration
+                xladd_derive_runtime::__private::keep_register_id(#name);
+            }
+        })
+        .collect::<Vec<_>>();
+    // register_if is checked each time the function is about to be registered
+    let condition = match &register_if {
+        Some(predicate) => {
+            let predicate_str = quote!(#predicate).to_string().replace(' ', "");
+            quote! {
+                if !#predicate() {
+                    log::info!("{} not registered as {} is false", #xl_function_str, #predicate_str);
+                    return;
+                }
+                log::info!("{} registered as {} is true", #xl_function_str, #predicate_str);
+            }
+        }
+        None => quote!(),
+    };
+    // Calls through an old name can log that it is deprecated, once per session
+    let alias_exports = alias_functions
+        .iter()
+        .map(|alias| {
+            let warning = if deprecate_aliases {
+                let alias = alias.to_string();
+                quote! {
+                    static WARNED: std::sync::Once = std::sync::Once::new();
+                    WARNED.call_once(|| log::warn!("{} is deprecated, use {}", #alias, #xl_function_str));
+                }
+            } else {
+                quote!()
+            };
+            if async_function {
+                quote! {
+                    #(#cfg_attrs)*
+                    #[no_mangle]
+                    pub extern "stdcall" fn #alias(#(#lpx_oper_args),* ,return_handle: LPXLOPER12) {
+                        #warning
+                        #xl_function(#(#caller_args),*, return_handle)
+                    }
+                }
+            } else {
+                quote! {
+                    #(#cfg_attrs)*
+                    #[no_mangle]
+                    pub extern "stdcall" fn #alias(#(#lpx_oper_args),*) -> LPXLOPER12 {
+                        #warning
+                        #xl_function(#(#caller_args),*)
+                    }
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    // Describe the function for tools that don't want to parse the source. rustc strips
+    // `#[cfg]` before expanding the macro, but if one is still here there's no telling
+    // whether the function is built, so it's left out.
+    if cfg_attrs.is_empty() {
+        manifest::write(&manifest::Entry {
+            export_name: &xl_function_str,
+            rust_name: &name,
+            aliases: alias_functions.iter().map(ToString::to_string).collect(),
+            parameters: item
+                .sig
+                .inputs
+                .iter()
+                .filter_map(|arg| match arg {
+                    FnArg::Typed(typed_arg) => {
+                        let pat = &typed_arg.pat;
+                        let ty = &typed_arg.ty;
+                        let name = quote!(#pat).to_string();
+                        let help = arg_docs.iter().find(|(n, _)| *n == name).map(|(_, v)| v.clone());
+                        Some((name, manifest::type_name(&quote!(#ty)), help.unwrap_or_default()))
+                    }
+                    FnArg::Receiver(_) => None,
+                })
+                .collect(),
+            return_type: match &item.sig.output {
+                syn::ReturnType::Default => "()".to_string(),
+                syn::ReturnType::Type(_, ty) => manifest::type_name(&quote!(#ty)),
+            },
+            type_string: &q_args,
+            category,
+            help: docs.as_deref().unwrap_or_default(),
+            returns: ret.as_deref().unwrap_or_default(),
+            async_function,
+            thread_safe,
+            hidden,
+            deprecate_aliases,
+            register_if: register_if.as_ref().map(|p| manifest::type_name(&quote!(#p))),
+            span: item.sig.ident.span(),
+        });
+    }
     // Async function
     if async_function {
         let wrapper = quote! {
              // Error handler
-             fn #error_handler_function(#(#variant_args),*, return_handle: LPXLOPER12) -> Result<Variant, Box<dyn std::error::Error>> {
+             #(#cfg_attrs)*
+             fn #error_handler_function(#(#variant_args),*, return_handle: LPXLOPER12) -> Result<Variant, xladd_derive_runtime::Failure> {
                 log::trace!("{} called [*ASYNC*] ..waiting for results",stringify!(#xl_function));
-                #(#convert_to_owned_rust_types)*;
+                // A panic in a conversion or in the function must not unwind into Excel or
+                // leave the cell waiting
+                let converted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                    || -> Result<_, xladd_derive_runtime::Failure> {
+                        #convert_options
+                        #(#convert_to_owned_rust_types)*;
+                        Ok((#(#caller_args,)*))
+                    },
+                ));
+                let (#(#caller_args,)*) = match converted {
+                    Ok(args) => args?,
+                    Err(_) => return Err(#panicked),
+                };
                 let raw_ptr = xladd::variant::XLOPERPtr(return_handle);
                 std::thread::spawn(move ||{
-                    #(#convert_to_ref_rust_types)*;
-                    match #func(#(#caller_args),*) {
-                        Ok(v) => {
+                    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                        || -> Result<Variant, xladd_derive_runtime::Failure> {
+                            #(#convert_to_ref_rust_types)*;
+                            let v = #call.map_err(#to_failure)?;
                             log::trace!("Results [{:?}]",v);
-                            xladd::entrypoint::excel12(
-                                                xladd::xlcall::xlAsyncReturn,
-                                                &mut [Variant::from(raw_ptr), v]);
-                        }
-                        Err(e) => {
+                            Ok(xladd_derive_runtime::IntoXl::into_xl_with(v, &#return_options))
+                        },
+                    ));
+                    let result = match res {
+                        Ok(Ok(v)) => v,
+                        Ok(Err(e)) => {
                             log::error!("Error {:?}",e.to_string());
-                            xladd::entrypoint::excel12(
-                                                xladd::xlcall::xlAsyncReturn,
-                                                &mut [Variant::from(raw_ptr), Variant::from(e.to_string())]);
+                            e.into_xl(#error_style)
                         }
-                    }
+                        Err(_) => #panicked.into_xl(#error_style),
+                    };
+                    xladd::entrypoint::excel12(
+                                        xladd::xlcall::xlAsyncReturn,
+                                        &mut [Variant::from(raw_ptr), result]);
                 });
                 Ok(Variant::default())
             }
             // Excel function
+            #(#cfg_attrs)*
             #[no_mangle]
             pub extern "stdcall" fn #xl_function(#(#lpx_oper_args),* ,return_handle: LPXLOPER12) {
                 #(#to_variant)*
@@ -450,58 +523,267 @@ pub fn xl_func(attr: TokenStream, input: TokenStream) -> TokenStream {
                         let raw_ptr = xladd::variant::XLOPERPtr(return_handle);
                         xladd::entrypoint::excel12(
                             xladd::xlcall::xlAsyncReturn,
-                            &mut [xladd::variant::Variant::from(raw_ptr), xladd::variant::Variant::from(e.to_string())],
+                            &mut [xladd::variant::Variant::from(raw_ptr), e.into_xl(#error_style)],
                         );
                     },
                 }
             }
+            // Aliases share the wrapper above
+            #(#alias_exports)*
 
+            #(#cfg_attrs)*
             pub (crate) fn #register_function(reg: &xladd::registrator::Reg) {
-                reg.add(#xl_function_str,#q_args,#caller_args_str,#category,#docs_ret,&[#(#args),*]);
+                #condition
+                #(#registrations)*
+            }
+            // Picked up by register_all
+            #(#cfg_attrs)*
+            xladd_derive_runtime::__private::inventory::submit! {
+                xladd_derive_runtime::Registration {
+                    name: #xl_function_str,
+                    register: #register_function,
+                }
             }
             // User function
-            #item
+            #user_item
         };
         wrapper.into()
     } else {
         let wrapper = quote! {
             // Error handler
-            fn #error_handler_function(#(#variant_args),*) -> Result<xladd::variant::Variant, Box<dyn std::error::Error>> {
+            #(#cfg_attrs)*
+            fn #error_handler_function(#(#variant_args),*) -> Result<xladd::variant::Variant, xladd_derive_runtime::Failure> {
                 log::trace!("{} called",stringify!(#xl_function));
-                #(#convert_to_owned_rust_types)*;
-                #(#convert_to_ref_rust_types)*;
-                let res = std::panic::catch_unwind(|| #func(#(#caller_args),*));
-                match res {
-                    Ok(result) => {
-                        let res = result?;
+                // Conversions are user code too, so they run inside catch_unwind with the
+                // function. A panic must not unwind into Excel.
+                let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(
+                    || -> Result<xladd::variant::Variant, xladd_derive_runtime::Failure> {
+                        #convert_options
+                        #(#convert_to_owned_rust_types)*;
+                        #(#convert_to_ref_rust_types)*;
+                        let res = #call.map_err(#to_failure)?;
                         log::trace!("Results [{:?}]",res);
-                        #output        
-                    }
-                    Err(_) => {
-                        log::error!("Unexpected error while calling function"); 
-                        Err("Error when trying to execute function, check for invalid values, ranges, or #n/a".into())
-                    }
+                        #output
+                    },
+                ));
+                match res {
+                    Ok(result) => result,
+                    Err(_) => Err(#panicked),
                 }
             }
             // Excel function
+            #(#cfg_attrs)*
             #[no_mangle]
             pub extern "stdcall" fn #xl_function(#(#lpx_oper_args),*)  -> LPXLOPER12 {
                 #(#to_variant)*
                 match #error_handler_function(#(#caller_args),*) {
-                    Ok(v) => LPXLOPER12::from(v),
+                    Ok(v) => #return_value(v),
                     Err(e) => {
                         log::error!("{}",e.to_string());
-                        LPXLOPER12::from(xladd::variant::Variant::from(e.to_string().as_str()))
+                        #return_value(e.into_xl(#error_style))
                     },
                 }
             }
+            // Aliases share the wrapper above
+            #(#alias_exports)*
 
+            #(#cfg_attrs)*
             pub (crate) fn #register_function(reg: &xladd::registrator::Reg) {
-                reg.add(#xl_function_str,#q_args,#caller_args_str,#category,#docs_ret,&[#(#args),*]);
+                #condition
+                #(#registrations)*
+            }
+            // Picked up by register_all
+            #(#cfg_attrs)*
+            xladd_derive_runtime::__private::inventory::submit! {
+                xladd_derive_runtime::Registration {
+                    name: #xl_function_str,
+                    register: #register_function,
+                }
             }
             // User function
-            #item
+            #user_item
         };
     wrapper.into()
     }   
-}
\ No newline at end of file
+}
+
+/// Reads the `name = "value"` pairs given to an attribute macro
+fn attr_params(attr: TokenStream) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    let tree = attr.into_iter().collect::<Vec<TokenTree>>();
+    for chunk in tree.as_slice().windows(3) {
+        match chunk {
+            [TokenTree::Ident(i),TokenTree::Punct(_),TokenTree::Literal(l)] => {
+                let l = l.to_string();
+                params.insert(i.to_string(),l[1..l.len()-1].to_string());
+            },
+            _ => (),
+        }
+    }
+    params
+}
+
+/// Reads a `name = path::to::item` path given to an attribute macro, which may also be quoted
+fn attr_path(attr: TokenStream, name: &str) -> Option<syn::Path> {
+    let tree = attr.into_iter().collect::<Vec<TokenTree>>();
+    let start = tree.as_slice().windows(2).position(|chunk| match chunk {
+        [TokenTree::Ident(i), TokenTree::Punct(p)] => i.to_string() == name && p.as_char() == '=',
+        _ => false,
+    })?;
+    let value = tree[start + 2..]
+        .iter()
+        .take_while(|t| !matches!(t, TokenTree::Punct(p) if p.as_char() == ','))
+        .cloned()
+        .collect::<TokenStream>();
+    let path = match syn::parse::<syn::LitStr>(value.clone()) {
+        Ok(lit) => lit.parse::<syn::Path>(),
+        Err(_) => syn::parse::<syn::Path>(value),
+    };
+    Some(path.unwrap_or_else(|_| panic!("{} must be a path, such as {} = my_mod::predicate", name, name)))
+}
+
+/// Reads a `name = ["a", "b"]` list given to an attribute macro
+fn attr_list(attr: TokenStream, name: &str) -> Vec<String> {
+    let tree = attr.into_iter().collect::<Vec<TokenTree>>();
+    for chunk in tree.as_slice().windows(3) {
+        if let [TokenTree::Ident(i), TokenTree::Punct(_), TokenTree::Group(g)] = chunk {
+            if i.to_string() == name && g.delimiter() == Delimiter::Bracket {
+                return g
+                    .stream()
+                    .into_iter()
+                    .filter_map(|t| match t {
+                        TokenTree::Literal(l) => {
+                            let l = l.to_string();
+                            Some(l[1..l.len() - 1].to_string())
+                        }
+                        _ => None,
+                    })
+                    .collect();
+            }
+        }
+    }
+    Vec::new()
+}
+
+/// Reads the `#[xl(name = "value", flag, ...)]` attributes on a parameter, struct or field.
+/// Flags are recorded with a value of "true"
+fn xl_options(attrs: &[syn::Attribute]) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    for attr in attrs.iter().filter(|attr| attr.path.is_ident("xl")) {
+        match attr.parse_meta() {
+            Ok(syn::Meta::List(list)) => {
+                for nested in list.nested.iter() {
+                    match nested {
+                        syn::NestedMeta::Meta(syn::Meta::NameValue(syn::MetaNameValue {
+                            path,
+                            lit: syn::Lit::Str(value),
+                            ..
+                        })) => {
+                            let name = path.get_ident().map(ToString::to_string).unwrap_or_default();
+                            params.insert(name, value.value());
+                        }
+                        syn::NestedMeta::Meta(syn::Meta::Path(path)) => {
+                            let name = path.get_ident().map(ToString::to_string).unwrap_or_default();
+                            params.insert(name, "true".to_string());
+                        }
+                        _ => panic!("Expected #[xl(name = \"value\")]"),
+                    }
+                }
+            }
+            _ => panic!("Expected #[xl(name = \"value\")]"),
+        }
+    }
+    params
+}
+
+fn coerce_policy(v: &str) -> proc_macro2::TokenStream {
+    match v {
+        "strict" => quote!(xladd_derive_runtime::Coerce::Strict),
+        "lenient" => quote!(xladd_derive_runtime::Coerce::Lenient),
+        _ => panic!("coerce must be \"strict\" or \"lenient\", not {}", v),
+    }
+}
+
+/// Maps a declared parameter type to the owned type that is read with `FromXl`, and the
+/// statements that turn the owned value back into the declared type before the call
+fn param_conversion(
+    arg_name: &proc_macro2::TokenStream,
+    ty: &syn::Type,
+) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
+    match ty {
+        syn::Type::Reference(r) => match &*r.elem {
+            // &str
+            elem if is_str(elem) => (quote!(String), quote!(let #arg_name = #arg_name.as_str();)),
+            syn::Type::Slice(s) => match &*s.elem {
+                // &[&str]
+                syn::Type::Reference(e) if is_str(&e.elem) => (
+                    quote!(Vec<String>),
+                    quote!(
+                        let #arg_name = #arg_name.iter().map(AsRef::as_ref).collect::<Vec<&str>>();
+                        let #arg_name = #arg_name.as_slice();
+                    ),
+                ),
+                // &[f64], &[String], &[T]
+                syn::Type::Path(elem) => (quote!(Vec<#elem>), quote!(let #arg_name = #arg_name.as_slice();)),
+                _ => panic!("Only slices of &[&str] or &[T] supported"),
+            },
+            // &String, &Vec<f64>, &T
+            elem => {
+                let (owned_type, reference_type) = param_conversion(arg_name, elem);
+                (owned_type, quote!(#reference_type let #arg_name = &#arg_name;))
+            }
+        },
+        syn::Type::Path(p) => {
+            let segment = p.path.segments.last().expect("Empty type path");
+            let ident = segment.ident.to_string();
+            match ident.as_str() {
+                "Cow" => {
+                    let inner = owned_param_type(generic_type(segment, "Cow"));
+                    (inner, quote!(let #arg_name = std::borrow::Cow::Owned(#arg_name);))
+                }
+                "Box" | "Rc" | "Arc" => {
+                    let inner = owned_param_type(generic_type(segment, &ident));
+                    (inner, quote!(let #arg_name = #arg_name.into();))
+                }
+                // String, Vec<String>, Vec<f64>, Array2<f64>, T
+                _ => (quote!(#p), quote!()),
+            }
+        }
+        _ => panic!("Type not covered"),
+    }
+}
+
+/// The owned type behind a `Cow`, `Box`, `Rc` or `Arc` parameter
+fn owned_param_type(ty: &syn::Type) -> proc_macro2::TokenStream {
+    match ty {
+        ty if is_str(ty) => quote!(String),
+        syn::Type::Slice(s) => match &*s.elem {
+            syn::Type::Path(elem) => quote!(Vec<#elem>),
+            _ => panic!("Only slices of [T] supported inside a Cow, Box, Rc or Arc"),
+        },
+        syn::Type::Path(p) => quote!(#p),
+        _ => panic!("Type not covered"),
+    }
+}
+
+/// The first type argument of a generic such as `Box<T>`, skipping any lifetimes
+fn generic_type<'a>(segment: &'a syn::PathSegment, name: &str) -> &'a syn::Type {
+    match &segment.arguments {
+        syn::PathArguments::AngleBracketed(generic_args) => generic_args
+            .args
+            .iter()
+            .find_map(|arg| match arg {
+                syn::GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("Expected a type argument for {}", name)),
+        _ => panic!("Expected a type argument for {}", name),
+    }
+}
+
+fn is_str(ty: &syn::Type) -> bool {
+    match ty {
+        syn::Type::Path(p) => p.path.is_ident("str"),
+        _ => false,
+    }
+}
@@ -0,0 +1,181 @@
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// What a build records about one `xl_func`, written out as JSON for documentation sites,
+/// test generation and API reviews
+pub struct Entry<'a> {
+    pub export_name: &'a str,
+    pub rust_name: &'a str,
+    pub aliases: Vec<String>,
+    /// Name, type and help text of each parameter
+    pub parameters: Vec<(String, String, String)>,
+    pub return_type: String,
+    pub type_string: &'a str,
+    pub category: &'a str,
+    pub help: &'a str,
+    /// The `* ret -` line of the doc comment
+    pub returns: &'a str,
+    pub async_function: bool,
+    pub thread_safe: bool,
+    pub hidden: bool,
+    pub deprecate_aliases: bool,
+    pub register_if: Option<String>,
+    pub span: proc_macro2::Span,
+}
+
+/// The directory manifests go to: `XLADD_MANIFEST_DIR` if set, taken relative to the crate
+/// rather than to wherever rustc runs, otherwise `xladd-manifest` under the crate's `OUT_DIR`
+/// when it has a build script
+fn manifest_dir() -> Option<PathBuf> {
+    match std::env::var_os("XLADD_MANIFEST_DIR") {
+        Some(dir) => match std::env::var_os("CARGO_MANIFEST_DIR") {
+            Some(crate_dir) => Some(PathBuf::from(crate_dir).join(dir)),
+            None => Some(PathBuf::from(dir)),
+        },
+        None => std::env::var_os("OUT_DIR").map(|dir| PathBuf::from(dir).join("xladd-manifest")),
+    }
+}
+
+/// Writes `<export_name>.json`. A manifest is only a by-product, so failing to write one
+/// doesn't fail the build. Files are never removed, see the README for clearing out
+/// functions that were renamed or deleted.
+pub fn write(entry: &Entry) {
+    if let Some(dir) = manifest_dir() {
+        let _ = std::fs::create_dir_all(&dir);
+        let _ = std::fs::write(dir.join(format!("{}.json", entry.export_name)), entry.to_json());
+    }
+}
+
+impl Entry<'_> {
+    fn to_json(&self) -> String {
+        let strings = |v: &[String]| v.iter().map(|s| json(s)).collect::<Vec<_>>().join(", ");
+        let parameters = self
+            .parameters
+            .iter()
+            .map(|(name, ty, help)| {
+                format!(
+                    "\n    {{\"name\": {}, \"type\": {}, \"help\": {}}}",
+                    json(name),
+                    json(ty),
+                    json(help)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        // Locations inside a macro are only known from rustc 1.88, older compilers give line 0
+        let start = self.span.start();
+        let source = if start.line == 0 {
+            "null".to_string()
+        } else {
+            format!(
+                "{{\"file\": {}, \"line\": {}, \"column\": {}}}",
+                json(&self.span.file()),
+                start.line,
+                start.column + 1
+            )
+        };
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            r#"{{
+  "export_name": {},
+  "rust_name": {},
+  "aliases": [{}],
+  "parameters": [{}],
+  "return_type": {},
+  "type_string": {},
+  "category": {},
+  "help": {},
+  "returns": {},
+  "flags": {{
+    "async": {},
+    "thread_safe": {},
+    "hidden": {},
+    "deprecate_aliases": {},
+    "register_if": {}
+  }},
+  "source": {}
+}}
+"#,
+            json(self.export_name),
+            json(self.rust_name),
+            strings(&self.aliases),
+            if parameters.is_empty() { parameters } else { parameters + "\n  " },
+            json(&self.return_type),
+            json(self.type_string),
+            json(self.category),
+            json(self.help),
+            json(self.returns),
+            self.async_function,
+            self.thread_safe,
+            self.hidden,
+            self.deprecate_aliases,
+            self.register_if.as_deref().map_or("null".to_string(), json),
+            source,
+        );
+        out
+    }
+}
+
+/// A JSON string literal
+fn json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Tidies the spacing of a type printed from its tokens, `Vec < & str >` becomes `Vec<&str>`
+pub fn type_name(tokens: &proc_macro2::TokenStream) -> String {
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    let mut out = String::new();
+    for token in tokens.to_string().split(' ') {
+        if let (Some(prev), Some(next)) = (out.chars().last(), token.chars().next()) {
+            if prev == ',' || (word(prev) && (word(next) || next == '\'')) {
+                out.push(' ');
+            }
+        }
+        out.push_str(token);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json, type_name};
+    use quote::quote;
+
+    #[test]
+    fn escapes_json_strings() {
+        assert_eq!(json("plain"), r#""plain""#);
+        assert_eq!(json(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(json(r"C:\temp"), r#""C:\\temp""#);
+        assert_eq!(json("a\nb\tc\rd"), r#""a\nb\tc\rd""#);
+        assert_eq!(json("\u{1}\u{1f}"), r#""\u0001\u001f""#);
+        assert_eq!(json("née 😀"), "\"née 😀\"");
+    }
+
+    #[test]
+    fn tidies_type_names() {
+        assert_eq!(type_name(&quote!(f64)), "f64");
+        assert_eq!(type_name(&quote!(Vec<&'a str>)), "Vec<&'a str>");
+        assert_eq!(type_name(&quote!(HashMap<String, f64>)), "HashMap<String, f64>");
+        assert_eq!(type_name(&quote!(&mut T)), "&mut T");
+        assert_eq!(type_name(&quote!(Box<dyn std::error::Error>)), "Box<dyn std::error::Error>");
+        assert_eq!(type_name(&quote!((Vec<f64>, usize))), "(Vec<f64>, usize)");
+        assert_eq!(type_name(&quote!(&[&str])), "&[&str]");
+    }
+}